@@ -0,0 +1,82 @@
+//
+// sprocketnes/console.rs
+//
+
+use apu::Apu;
+use audio::OutputBuffer;
+use cpu::Cpu;
+use input::{ButtonState, Input};
+use mapper::Mapper;
+use mapper;
+use mem::MemMap;
+use ppu::{Oam, Ppu, Vram};
+use rom::{Rom, RomError};
+
+use libc::uint8_t;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+//
+// The whole console
+//
+
+// A NES: the CPU, with the PPU, APU, controllers, and cartridge mapped into its memory.
+pub struct Nes {
+    pub cpu: Cpu<MemMap>,
+}
+
+impl Nes {
+    // Builds a console for the given cartridge, playing sound into `audio_buffer` if there is
+    // one, and resets the CPU. Fails if the cartridge's mapper isn't supported.
+    pub fn new(rom: Box<Rom>, audio_buffer: Option<*mut OutputBuffer>) -> Result<Nes,RomError> {
+        let mirroring = rom.header.mirroring();
+        let mapper: Box<Mapper+Send> = try!(mapper::create_mapper(rom));
+        let mapper = Rc::new(RefCell::new(mapper));
+        let mut vram = Vram::new(mapper.clone());
+        vram.set_mirroring(mirroring);
+        let ppu = Ppu::new(vram, Oam::new());
+        let input = Input::new();
+        let apu = Apu::new(audio_buffer);
+        let memmap = MemMap::new(ppu, input, mapper, apu);
+
+        let mut cpu = Cpu::new(memmap);
+        cpu.reset();
+        Ok(Nes {
+            cpu: cpu,
+        })
+    }
+
+    // Runs the console until the PPU finishes a frame and returns the screen, in the same
+    // layout as `Ppu::screen`.
+    pub fn run_frame(&mut self) -> &[uint8_t] {
+        loop {
+            self.cpu.step();
+
+            let ppu_result = self.cpu.mem.ppu.step(self.cpu.cy);
+            match ppu_result.timing_error {
+                Some(err) => panic!("{}", err),
+                None => {}
+            }
+            if ppu_result.interrupts.vblank_nmi() {
+                self.cpu.nmi();
+            } else if ppu_result.interrupts.mapper_irq() {
+                self.cpu.irq();
+            }
+
+            self.cpu.mem.apu.step(self.cpu.cy);
+
+            if ppu_result.new_frame {
+                break;
+            }
+        }
+
+        self.cpu.mem.apu.play_channels();
+        self.cpu.mem.ppu.screen.as_slice()
+    }
+
+    // Sets the buttons held on the controller in the given port (0 or 1). The game sees them the
+    // next time it strobes and reads $4016 or $4017.
+    pub fn set_buttons(&mut self, port: uint8_t, buttons: ButtonState) {
+        self.cpu.mem.input.set_buttons(port, buttons);
+    }
+}
@@ -15,7 +15,9 @@ use sdl2::render::{ACCELERATED,
 use sdl2::video::{WindowPos, Window, INPUT_FOCUS};
 use sdl2;
 
-use libc::{int32_t, uint8_t};
+use libc::{int32_t, uint8_t, uint32_t};
+use std::collections::HashMap;
+use std::io::{IoResult, Writer};
 
 const SCREEN_WIDTH: uint = 256;
 const SCREEN_HEIGHT: uint = 240;
@@ -264,6 +266,182 @@ impl StatusLine {
     }
 }
 
+//
+// Frame utilities
+//
+
+// Stitches two 256x240 RGB frames together horizontally into one 512x240 frame, as for the two
+// screens of a VS DualSystem board.
+pub fn compose_side_by_side(left: &[uint8_t], right: &[uint8_t]) -> Vec<uint8_t> {
+    assert!(left.len() == SCREEN_SIZE, "left frame has the wrong size");
+    assert!(right.len() == SCREEN_SIZE, "right frame has the wrong size");
+
+    let row_len = SCREEN_WIDTH * 3;
+    let mut result = Vec::with_capacity(SCREEN_SIZE * 2);
+    for y in range(0, SCREEN_HEIGHT) {
+        result.push_all(left.slice(y * row_len, (y + 1) * row_len));
+        result.push_all(right.slice(y * row_len, (y + 1) * row_len));
+    }
+    result
+}
+
+// Compares two frames in screen (BGR) order and returns the number of pixels that differ, along
+// with an image of `a` dimmed to a quarter of its brightness with the differing pixels in red.
+pub fn diff_frames(a: &[uint8_t], b: &[uint8_t]) -> (uint32_t, Vec<uint8_t>) {
+    assert!(a.len() == b.len(), "frames have different sizes");
+    assert!(a.len() % 3 == 0, "frames must be made of 3-byte pixels");
+
+    let mut count = 0;
+    let mut result = Vec::with_capacity(a.len());
+    for (pa, pb) in a.chunks(3).zip(b.chunks(3)) {
+        if pa == pb {
+            result.push_all([ pa[0] / 4, pa[1] / 4, pa[2] / 4 ].as_slice());
+        } else {
+            count += 1;
+            result.push_all([ 0, 0, 255 ].as_slice());
+        }
+    }
+    (count, result)
+}
+
+//
+// GIF recording
+//
+
+// GIF delays are in hundredths of a second, so 60 frames per second can't be expressed exactly.
+const GIF_FRAME_DELAY: u16 = 2;
+// Palette indices are 6 bits wide.
+const GIF_MIN_CODE_SIZE: uint = 6;
+
+// Records a sequence of frames as an animated GIF. Frames are given as 6-bit palette indices, one
+// byte per pixel (see `Ppu::indexed_screen`), and the 64-color palette becomes the GIF's
+// color table.
+pub struct GifRecorder {
+    palette: Vec<uint8_t>,          // 64 RGB triples
+    frames: Vec<Vec<uint8_t>>,      // LZW-compressed
+}
+
+impl GifRecorder {
+    // Creates a recorder using the given palette of 64 RGB triples (see `Ppu::rgb_palette`).
+    pub fn new(palette: &[uint8_t]) -> GifRecorder {
+        assert!(palette.len() == 64 * 3, "palette must have 64 RGB colors");
+        GifRecorder {
+            palette: palette.to_vec(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn push_frame(&mut self, indexed: &[uint8_t]) {
+        assert!(indexed.len() == SCREEN_WIDTH * SCREEN_HEIGHT, "indexed frame has the wrong size");
+        self.frames.push(lzw_compress(indexed, GIF_MIN_CODE_SIZE));
+    }
+
+    // Writes out the GIF, looping forever.
+    pub fn finish<W: Writer>(self, writer: &mut W) -> IoResult<()> {
+        // Header and logical screen descriptor, with a global color table of 64 entries.
+        try!(writer.write(b"GIF89a"));
+        try!(writer.write_le_u16(SCREEN_WIDTH as u16));
+        try!(writer.write_le_u16(SCREEN_HEIGHT as u16));
+        try!(writer.write(&[ 0xf5, 0, 0 ]));
+        try!(writer.write(self.palette.as_slice()));
+
+        // Netscape application extension, to loop the animation.
+        try!(writer.write(&[ 0x21, 0xff, 0x0b ]));
+        try!(writer.write(b"NETSCAPE2.0"));
+        try!(writer.write(&[ 0x03, 0x01, 0x00, 0x00, 0x00 ]));
+
+        for frame in self.frames.iter() {
+            // Graphic control extension, for the frame delay.
+            try!(writer.write(&[ 0x21, 0xf9, 0x04, 0x00 ]));
+            try!(writer.write_le_u16(GIF_FRAME_DELAY));
+            try!(writer.write(&[ 0x00, 0x00 ]));
+
+            // Image descriptor covering the whole screen.
+            try!(writer.write(&[ 0x2c, 0, 0, 0, 0 ]));
+            try!(writer.write_le_u16(SCREEN_WIDTH as u16));
+            try!(writer.write_le_u16(SCREEN_HEIGHT as u16));
+            try!(writer.write(&[ 0x00, GIF_MIN_CODE_SIZE as uint8_t ]));
+
+            // The image data, in sub-blocks of up to 255 bytes.
+            for block in frame.as_slice().chunks(255) {
+                try!(writer.write(&[ block.len() as uint8_t ]));
+                try!(writer.write(block));
+            }
+            try!(writer.write(&[ 0x00 ]));
+        }
+
+        writer.write(&[ 0x3b ])
+    }
+}
+
+// Packs variable-width codes into bytes, least significant bit first, as GIF requires.
+struct CodeWriter {
+    bytes: Vec<uint8_t>,
+    buffer: uint,
+    buffered_bits: uint,
+}
+
+impl CodeWriter {
+    fn write(&mut self, code: uint, size: uint) {
+        self.buffer |= code << self.buffered_bits;
+        self.buffered_bits += size;
+        while self.buffered_bits >= 8 {
+            self.bytes.push(self.buffer as uint8_t);
+            self.buffer >>= 8;
+            self.buffered_bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<uint8_t> {
+        if self.buffered_bits > 0 {
+            self.bytes.push(self.buffer as uint8_t);
+        }
+        self.bytes
+    }
+}
+
+// Compresses `data` with GIF's variant of LZW. Codes start at `min_code_size + 1` bits and grow
+// up to 12 bits, at which point the table is cleared.
+fn lzw_compress(data: &[uint8_t], min_code_size: uint) -> Vec<uint8_t> {
+    let clear_code = 1 << min_code_size;
+    let end_code = clear_code + 1;
+
+    let mut writer = CodeWriter { bytes: Vec::new(), buffer: 0, buffered_bits: 0 };
+    let mut table: HashMap<(uint, uint8_t), uint> = HashMap::new();
+    let mut next_code = end_code + 1;
+    let mut code_size = min_code_size + 1;
+
+    writer.write(clear_code, code_size);
+    let mut prefix = data[0] as uint;
+    for &byte in data.slice_from(1).iter() {
+        match table.get(&(prefix, byte)) {
+            Some(&code) => {
+                prefix = code;
+                continue
+            }
+            None => {}
+        }
+
+        writer.write(prefix, code_size);
+        if next_code == 4096 {
+            writer.write(clear_code, code_size);
+            table.clear();
+            next_code = end_code + 1;
+            code_size = min_code_size + 1;
+        } else {
+            table.insert((prefix, byte), next_code);
+            if next_code == 1 << code_size {
+                code_size += 1;
+            }
+            next_code += 1;
+        }
+        prefix = byte as uint;
+    }
+    writer.write(prefix, code_size);
+    writer.write(end_code, code_size);
+    writer.finish()
+}
+
 //
 // Screen scaling
 //
@@ -74,8 +74,73 @@ pub struct GamePadState {
     strobe_state: StrobeState,
 }
 
+impl GamePadState {
+    fn new() -> GamePadState {
+        GamePadState {
+            left: false,
+            down: false,
+            up: false,
+            right: false,
+            a: false,
+            b: false,
+            select: false,
+            start: false,
+
+            strobe_state: StrobeState{val: STROBE_STATE_A}
+        }
+    }
+
+    fn set_buttons(&mut self, buttons: &ButtonState) {
+        self.left = buttons.left;
+        self.down = buttons.down;
+        self.up = buttons.up;
+        self.right = buttons.right;
+        self.a = buttons.a;
+        self.b = buttons.b;
+        self.select = buttons.select;
+        self.start = buttons.start;
+    }
+
+    // Reads the next button in the strobe order.
+    fn read(&mut self) -> uint8_t {
+        let result = self.strobe_state.get(self) as uint8_t;
+        self.strobe_state.next();
+        result
+    }
+}
+
+// The buttons held on a game pad, for driving the emulator from code (test harnesses, movie
+// playback) instead of the keyboard.
+#[deriving(Copy, PartialEq, Eq, Show)]
+pub struct ButtonState {
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+}
+
+impl ButtonState {
+    pub fn new() -> ButtonState {
+        ButtonState {
+            a: false,
+            b: false,
+            select: false,
+            start: false,
+            up: false,
+            down: false,
+            left: false,
+            right: false,
+        }
+    }
+}
+
 pub struct Input {
-    pub gamepad_0: GamePadState
+    pub gamepad_0: GamePadState,    // Read through $4016.
+    pub gamepad_1: GamePadState,    // Read through $4017.
 }
 
 #[deriving(Copy)]
@@ -89,18 +154,18 @@ pub enum InputResult {
 impl Input {
     pub fn new() -> Input {
         Input {
-            gamepad_0: GamePadState {
-                left: false,
-                down: false,
-                up: false,
-                right: false,
-                a: false,
-                b: false,
-                select: false,
-                start: false,
-
-                strobe_state: StrobeState{val: STROBE_STATE_A}
-            }
+            gamepad_0: GamePadState::new(),
+            gamepad_1: GamePadState::new(),
+        }
+    }
+
+    // Sets the buttons held on the game pad in the given port (0 or 1), replacing whatever the
+    // keyboard has set.
+    pub fn set_buttons(&mut self, port: uint8_t, buttons: ButtonState) {
+        match port {
+            0 => self.gamepad_0.set_buttons(&buttons),
+            1 => self.gamepad_1.set_buttons(&buttons),
+            _ => panic!("invalid controller port")
         }
     }
 
@@ -152,9 +217,9 @@ impl Input {
 impl Mem for Input {
     fn loadb(&mut self, addr: uint16_t) -> uint8_t {
         if addr == 0x4016 {
-            let result = self.gamepad_0.strobe_state.get(&self.gamepad_0) as uint8_t;
-            self.gamepad_0.strobe_state.next();
-            result
+            self.gamepad_0.read()
+        } else if addr == 0x4017 {
+            self.gamepad_1.read()
         } else {
             0
         }
@@ -165,6 +230,7 @@ impl Mem for Input {
             // FIXME: This is not really accurate; you're supposed to not reset until you see
             // 1 strobed than 0. But I doubt this will break anything.
             self.gamepad_0.strobe_state.reset();
+            self.gamepad_1.strobe_state.reset();
         }
     }
 }
@@ -4,23 +4,16 @@
 // Author: Patrick Walton
 //
 
-use apu::Apu;
 use audio;
-use cpu::Cpu;
+use console::Nes;
 use gfx::{Gfx, Scale};
-use input::{Input, InputResult};
-use mapper::Mapper;
-use mapper;
-use mem::MemMap;
-use ppu::{Oam, Ppu, Vram};
+use input::InputResult;
 use rom::Rom;
 use util::Save;
 use util;
 
 use libc::uint64_t;
-use std::cell::RefCell;
 use std::io::File;
-use std::rc::Rc;
 
 #[cfg(debug)]
 fn record_fps(last_time: &mut uint64_t, frames: &mut uint) {
@@ -96,55 +89,47 @@ pub fn start() {
     };
 
     let rom_path = options.rom_path.as_slice();
-    let rom = box Rom::from_path(&Path::new(rom_path));
+    let rom = match Rom::from_path(&Path::new(rom_path)) {
+        Ok(rom) => box rom,
+        Err(err) => {
+            println!("Failed to load ROM: {}", err);
+            return;
+        }
+    };
     println!("Loaded ROM:\n{}", rom.header.to_str());
 
     let mut gfx = Gfx::new(options.scale);
     let audio_buffer = audio::open();
 
-    let mapper: Box<Mapper+Send> = mapper::create_mapper(rom);
-    let mapper = Rc::new(RefCell::new(mapper));
-    let ppu = Ppu::new(Vram::new(mapper.clone()), Oam::new());
-    let input = Input::new();
-    let apu = Apu::new(audio_buffer);
-    let memmap = MemMap::new(ppu, input, mapper, apu);
-    let mut cpu = Cpu::new(memmap);
-
     // TODO: Add a flag to not reset for nestest.log
-    cpu.reset();
+    let mut nes = match Nes::new(rom, audio_buffer) {
+        Ok(nes) => nes,
+        Err(err) => {
+            println!("Failed to load ROM: {}", err);
+            return;
+        }
+    };
 
     let mut last_time = util::current_time_millis();
     let mut frames = 0;
 
     loop {
-        cpu.step();
-
-        let ppu_result = cpu.mem.ppu.step(cpu.cy);
-        if ppu_result.vblank_nmi {
-            cpu.nmi();
-        } else if ppu_result.scanline_irq {
-            cpu.irq();
-        }
-
-        cpu.mem.apu.step(cpu.cy);
-
-        if ppu_result.new_frame {
-            gfx.tick();
-            gfx.composite(&mut *cpu.mem.ppu.screen);
-            record_fps(&mut last_time, &mut frames);
-            cpu.mem.apu.play_channels();
-
-            match cpu.mem.input.check_input() {
-                InputResult::Continue => {}
-                InputResult::Quit => break,
-                InputResult::SaveState => {
-                    cpu.save(&mut File::create(&Path::new("state.sav")).unwrap());
-                    gfx.status_line.set("Saved state".to_string());
-                }
-                InputResult::LoadState => {
-                    cpu.load(&mut File::open(&Path::new("state.sav")).unwrap());
-                    gfx.status_line.set("Loaded state".to_string());
-                }
+        nes.run_frame();
+
+        gfx.tick();
+        gfx.composite(&mut *nes.cpu.mem.ppu.screen);
+        record_fps(&mut last_time, &mut frames);
+
+        match nes.cpu.mem.input.check_input() {
+            InputResult::Continue => {}
+            InputResult::Quit => break,
+            InputResult::SaveState => {
+                nes.cpu.save(&mut File::create(&Path::new("state.sav")).unwrap());
+                gfx.status_line.set("Saved state".to_string());
+            }
+            InputResult::LoadState => {
+                nes.cpu.load(&mut File::open(&Path::new("state.sav")).unwrap());
+                gfx.status_line.set("Loaded state".to_string());
             }
         }
     }
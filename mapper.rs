@@ -4,10 +4,12 @@
 // Author: Patrick Walton
 //
 
-use rom::Rom;
+use rom::{Rom, RomError};
+use util::Save;
 use util;
 
 use libc::{uint8_t, uint16_t};
+use std::io::File;
 
 #[deriving(PartialEq, Eq, Copy)]
 pub enum MapperResult {
@@ -21,18 +23,34 @@ pub trait Mapper {
     fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t;
     fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t);
     fn next_scanline(&mut self) -> MapperResult;
+
+    // The nametable mirroring the mapper has selected, or None if it's fixed by the cartridge's
+    // wiring, as given in the iNES header.
+    fn mirroring(&self) -> Option<Mirroring> { None }
+}
+
+type MapperConstructor = fn(Box<Rom>) -> Box<Mapper+Send>;
+
+// The one list of supported mappers, by iNES number.
+fn mapper_constructor(mapper: uint8_t) -> Option<MapperConstructor> {
+    match mapper {
+        0 => Some(Nrom::create as MapperConstructor),
+        1 => Some(SxRom::create as MapperConstructor),
+        4 => Some(TxRom::create as MapperConstructor),
+        _ => None,
+    }
 }
 
-pub fn create_mapper(rom: Box<Rom>) -> Box<Mapper+Send> {
-    match rom.header.ines_mapper() {
-        0 => {
-            box Nrom {
-                rom: rom,
-            } as Box<Mapper+Send>
-        },
-        1 => box SxRom::new(rom) as Box<Mapper+Send>,
-        4 => box TxRom::new(rom) as Box<Mapper+Send>,
-        _ => panic!("unsupported mapper")
+// Returns true if `create_mapper` can create the mapper with the given iNES number.
+pub fn is_supported(mapper: uint8_t) -> bool {
+    mapper_constructor(mapper).is_some()
+}
+
+pub fn create_mapper(rom: Box<Rom>) -> Result<Box<Mapper+Send>,RomError> {
+    let mapper = rom.header.ines_mapper();
+    match mapper_constructor(mapper) {
+        Some(create) => Ok(create(rom)),
+        None => Err(RomError::UnsupportedMapper(mapper)),
     }
 }
 
@@ -45,6 +63,27 @@ pub fn create_mapper(rom: Box<Rom>) -> Box<Mapper+Send> {
 // TODO: RAM.
 pub struct Nrom {
     pub rom: Box<Rom>,
+    chr_ram: bool,      // True if the cartridge has CHR-RAM, which is kept in `rom.chr`.
+}
+
+impl Nrom {
+    pub fn new(mut rom: Box<Rom>) -> Nrom {
+        // Cartridges without CHR-ROM have 8K of CHR-RAM instead. Tiles are read straight from it
+        // on every fetch, so writes show up on the next pixel rendered.
+        let chr_ram = rom.chr.len() == 0;
+        if chr_ram {
+            rom.chr = Vec::from_elem(8192, 0u8);
+        }
+
+        Nrom {
+            rom: rom,
+            chr_ram: chr_ram,
+        }
+    }
+
+    fn create(rom: Box<Rom>) -> Box<Mapper+Send> {
+        box Nrom::new(rom) as Box<Mapper+Send>
+    }
 }
 
 impl Mapper for Nrom {
@@ -59,7 +98,12 @@ impl Mapper for Nrom {
     }
     fn prg_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to PRG-ROM.
     fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t { self.rom.chr[addr as uint] }
-    fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
+    fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        // Writes only stick with CHR-RAM; CHR-ROM can't be stored to.
+        if self.chr_ram {
+            self.rom.chr[addr as uint] = val;
+        }
+    }
     fn next_scanline(&mut self) -> MapperResult { MapperResult::Continue }
 }
 
@@ -77,7 +121,7 @@ impl Deref<uint8_t> for SxCtrl {
     }
 }
 
-#[deriving(Copy)]
+#[deriving(Copy, PartialEq, Eq, Show)]
 pub enum Mirroring {
     OneScreenLower,
     OneScreenUpper,
@@ -85,6 +129,28 @@ pub enum Mirroring {
     Horizontal,
 }
 
+impl Save for Mirroring {
+    fn save(&mut self, fd: &mut File) {
+        let mut val: uint8_t = match *self {
+            Mirroring::OneScreenLower => 0,
+            Mirroring::OneScreenUpper => 1,
+            Mirroring::Vertical => 2,
+            Mirroring::Horizontal => 3,
+        };
+        val.save(fd)
+    }
+    fn load(&mut self, fd: &mut File) {
+        let mut val: uint8_t = 0;
+        val.load(fd);
+        *self = match val {
+            0 => Mirroring::OneScreenLower,
+            1 => Mirroring::OneScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        };
+    }
+}
+
 #[deriving(Copy)]
 enum SxPrgBankMode {
     Switch32K,      // Switch 32K at $8000, ignore low bit
@@ -101,6 +167,14 @@ impl SxCtrl {
             _     => panic!("can't happen")
         }
     }
+    fn mirroring(self) -> Mirroring {
+        match *self & 3 {
+            0 => Mirroring::OneScreenLower,
+            1 => Mirroring::OneScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
 }
 
 struct SxRegs {
@@ -139,6 +213,10 @@ impl SxRom {
             chr_ram: box() ([ 0, ..8192 ]),
         }
     }
+
+    fn create(rom: Box<Rom>) -> Box<Mapper+Send> {
+        box SxRom::new(rom) as Box<Mapper+Send>
+    }
 }
 
 impl Mapper for SxRom {
@@ -202,6 +280,8 @@ impl Mapper for SxRom {
     fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t) { self.chr_ram[addr as uint] = val }
 
     fn next_scanline(&mut self) -> MapperResult { MapperResult::Continue }
+
+    fn mirroring(&self) -> Option<Mirroring> { Some(self.regs.ctrl.mirroring()) }
 }
 
 //
@@ -251,6 +331,8 @@ struct TxRom {
     scanline_counter: uint8_t,
     irq_reload: uint8_t,             // Copied into the scanline counter when it hits zero.
     irq_enabled: bool,
+
+    mirroring: Option<Mirroring>,    // None until the game selects one.
 }
 
 impl TxRom {
@@ -267,9 +349,15 @@ impl TxRom {
             scanline_counter: 0,
             irq_reload: 0,
             irq_enabled: false,
+
+            mirroring: None,
         }
     }
 
+    fn create(rom: Box<Rom>) -> Box<Mapper+Send> {
+        box TxRom::new(rom) as Box<Mapper+Send>
+    }
+
     fn prg_bank_count(&self) -> uint8_t { self.rom.header.prg_rom_size * 2 }
 }
 
@@ -325,7 +413,15 @@ impl Mapper for TxRom {
                 }
             }
         } else if addr < 0xc000 {
-            // TODO: Mirroring and PRG-RAM protect
+            if (addr & 1) == 0 {
+                // Mirroring.
+                self.mirroring = Some(if (val & 1) == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                });
+            }
+            // TODO: PRG-RAM protect
         } else if addr < 0xe000 {
             if (addr & 1) == 0 {
                 // IRQ latch.
@@ -375,5 +471,7 @@ impl Mapper for TxRom {
         }
         MapperResult::Continue
     }
+
+    fn mirroring(&self) -> Option<Mirroring> { self.mirroring }
 }
 
@@ -113,7 +113,7 @@ impl Mem for MemMap {
             self.ram.loadb(addr)
         } else if addr < 0x4000 {
             self.ppu.loadb(addr)
-        } else if addr == 0x4016 {
+        } else if addr == 0x4016 || addr == 0x4017 {
             self.input.loadb(addr)
         } else if addr <= 0x4018 {
             self.apu.loadb(addr)
@@ -136,6 +136,10 @@ impl Mem for MemMap {
         } else if addr < 0x6000 {
             // Nothing. FIXME: I think some mappers use regs in this area?
         } else {
+            // Mapper writes can switch CHR banks behind the PPU's back.
+            if addr >= 0x8000 {
+                self.ppu.invalidate_frame_cache();
+            }
             let mut mapper = self.mapper.borrow_mut();
             mapper.prg_storeb(addr, val)
         }
@@ -15,6 +15,7 @@ pub mod util;
 
 pub mod apu;
 pub mod audio;
+pub mod console;
 #[macro_escape]
 pub mod cpu;
 pub mod disasm;
@@ -23,6 +24,7 @@ pub mod input;
 pub mod main;
 pub mod mapper;
 pub mod mem;
+pub mod observe;
 pub mod ppu;
 pub mod rom;
 
@@ -0,0 +1,46 @@
+//
+// sprocketnes/observe.rs
+//
+
+use mem::Mem;
+
+use libc::{uint8_t, uint16_t};
+
+//
+// Memory access observers, for instrumenting a device without touching its implementation
+//
+
+/// Receives every access made through an `ObservedPpu`.
+pub trait MemObserver {
+    fn on_load(&mut self, addr: uint16_t, val: uint8_t);
+    fn on_store(&mut self, addr: uint16_t, val: uint8_t);
+}
+
+/// Wraps a memory-mapped device (usually the PPU) and reports each `loadb`/`storeb` to an
+/// observer after forwarding it to the device.
+pub struct ObservedPpu<P, O> {
+    pub inner: P,
+    pub observer: O,
+}
+
+impl<P: Mem, O: MemObserver> ObservedPpu<P, O> {
+    pub fn new(inner: P, observer: O) -> ObservedPpu<P, O> {
+        ObservedPpu { inner: inner, observer: observer }
+    }
+
+    pub fn unwrap(self) -> (P, O) {
+        (self.inner, self.observer)
+    }
+}
+
+impl<P: Mem, O: MemObserver> Mem for ObservedPpu<P, O> {
+    fn loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let val = self.inner.loadb(addr);
+        self.observer.on_load(addr, val);
+        val
+    }
+    fn storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        self.inner.storeb(addr, val);
+        self.observer.on_store(addr, val);
+    }
+}
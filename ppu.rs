@@ -4,14 +4,19 @@
 // Author: Patrick Walton
 //
 
-use mapper::{MapperResult, Mapper};
+use mapper::{MapperResult, Mapper, Mirroring};
 use mem::Mem;
-use util::{Save, debug_assert};
+use util::{Save, Xorshift, debug_assert};
 
-use libc::{uint8_t, uint16_t, uint64_t};
+use libc::{uint8_t, uint16_t, uint32_t, uint64_t};
 use std::cell::RefCell;
+use std::cmp;
+use std::fmt;
+use std::i32;
 use std::io::File;
+use std::mem;
 use std::rc::Rc;
+use std::time::Duration;
 
 //
 // Constants
@@ -19,11 +24,23 @@ use std::rc::Rc;
 
 pub static SCREEN_WIDTH: uint = 256;
 pub static SCREEN_HEIGHT: uint = 240;
-pub static CYCLES_PER_SCANLINE: uint64_t = 114;   // 29781 cycles per frame, 261 scanlines
+pub static DOTS_PER_CPU_CYCLE: uint64_t = 3;      // So a scanline is 113 2/3 CPU cycles
 pub static VBLANK_SCANLINE: uint = 241;
-pub static LAST_SCANLINE: uint = 261;
+pub static LAST_SCANLINE: uint = 261;             // The pre-render scanline
+pub static DOTS_PER_SCANLINE: uint = 341;
 
-static PALETTE: [uint8_t, ..192] = [
+// The layers a pixel can come from; see `Ppu::render_frame_with_layers`.
+pub static LAYER_BACKDROP: uint8_t = 0;
+pub static LAYER_BACKGROUND: uint8_t = 1;
+pub static LAYER_SPRITE_BELOW_BG: uint8_t = 2;
+pub static LAYER_SPRITE_ABOVE_BG: uint8_t = 3;
+
+// How long OAM survives without being refreshed by rendering before it starts to decay. This is a
+// little longer than the NTSC vertical blank, which games can rely on.
+static OAM_DECAY_CYCLES: uint64_t = 3000;
+
+// The colors produced by the 2C02 (NTSC) PPU.
+static NTSC_PALETTE: [uint8_t, ..192] = [
     124,124,124,    0,0,252,        0,0,188,        68,40,188,
     148,0,132,      168,0,32,       168,16,0,       136,20,0,
     80,48,0,        0,120,0,        0,104,0,        0,88,0,
@@ -42,6 +59,180 @@ static PALETTE: [uint8_t, ..192] = [
     0,252,252,      248,216,248,    0,0,0,          0,0,0
 ];
 
+// The colors produced by the 2C07 (PAL) PPU. These are decoded from the same composite signal
+// levels as the NTSC palette, but with YUV demodulation and the hue shift of the PAL colorburst.
+static PAL_PALETTE: [uint8_t, ..192] = [
+    102,102,102,    0,32,185,       36,3,208,       84,0,185,
+    120,0,122,      132,0,36,       120,10,0,       84,40,0,
+    36,69,0,        0,90,0,         0,95,0,         0,85,36,
+    0,62,122,       0,0,0,          0,0,0,          0,0,0,
+    174,174,174,    23,81,255,      87,43,255,      151,16,255,
+    198,9,201,      215,22,87,      198,53,0,       151,93,0,
+    87,132,0,       23,158,0,       0,166,0,        0,153,87,
+    0,122,201,      0,0,0,          0,0,0,          0,0,0,
+    255,255,255,    102,161,255,    167,122,255,    232,95,255,
+    255,87,255,     255,101,167,    255,132,51,     232,173,0,
+    167,212,0,      102,239,0,      54,246,51,      37,233,167,
+    54,201,255,     78,78,78,       0,0,0,          0,0,0,
+    255,255,255,    192,216,255,    219,200,255,    245,189,255,
+    255,186,255,    255,191,219,    255,204,171,    245,221,136,
+    219,237,123,    192,248,136,    172,251,171,    165,246,219,
+    172,233,255,    182,182,182,    0,0,0,          0,0,0
+];
+
+// The colors produced by the RGB PPUs (2C03, 2C04, and 2C05) used in arcade boards. These PPUs
+// output 3 bits per channel.
+static RGB_PALETTE: [uint8_t, ..192] = [
+    109,109,109,    0,36,146,       0,0,219,        109,73,219,
+    146,0,109,      182,0,109,      182,36,0,       146,73,0,
+    109,73,0,       36,73,0,        0,109,36,       0,146,0,
+    0,73,73,        0,0,0,          0,0,0,          0,0,0,
+    182,182,182,    0,109,219,      0,73,255,       146,0,255,
+    182,0,255,      255,0,146,      255,0,0,        219,109,0,
+    146,109,0,      36,146,0,       0,146,0,        0,182,109,
+    0,146,146,      0,0,0,          0,0,0,          0,0,0,
+    255,255,255,    109,182,255,    146,146,255,    219,109,255,
+    255,0,255,      255,109,255,    255,146,0,      255,182,0,
+    219,219,0,      109,219,0,      0,255,0,        73,255,219,
+    0,255,255,      0,0,0,          0,0,0,          0,0,0,
+    255,255,255,    182,219,255,    219,182,255,    255,182,255,
+    255,146,255,    255,182,182,    255,219,146,    255,255,73,
+    255,255,109,    182,255,73,     146,255,109,    73,255,219,
+    146,219,255,    0,0,0,          0,0,0,          0,0,0
+];
+
+// The 2C04 PPUs of the VS System use the 2C03 colors, but each revision scrambles the order of
+// the palette so that games only look right on the board they were made for. These map palette
+// indices to 2C03 palette indices.
+static RP2C04_0001_PERMUTATION: [uint8_t, ..64] = [
+    0x35, 0x23, 0x16, 0x22, 0x1c, 0x09, 0x1d, 0x15,
+    0x20, 0x00, 0x27, 0x05, 0x04, 0x28, 0x08, 0x20,
+    0x21, 0x3e, 0x1f, 0x29, 0x3c, 0x32, 0x36, 0x12,
+    0x3f, 0x2b, 0x2e, 0x1e, 0x3d, 0x2d, 0x24, 0x01,
+    0x0e, 0x31, 0x33, 0x2a, 0x2c, 0x0c, 0x1b, 0x14,
+    0x2e, 0x07, 0x34, 0x06, 0x13, 0x02, 0x26, 0x2e,
+    0x2e, 0x19, 0x10, 0x0a, 0x39, 0x03, 0x37, 0x17,
+    0x0f, 0x11, 0x0b, 0x0d, 0x38, 0x25, 0x18, 0x3a,
+];
+
+static RP2C04_0002_PERMUTATION: [uint8_t, ..64] = [
+    0x2e, 0x27, 0x18, 0x39, 0x3a, 0x25, 0x1c, 0x31,
+    0x16, 0x13, 0x38, 0x34, 0x20, 0x23, 0x3d, 0x1a,
+    0x09, 0x21, 0x06, 0x10, 0x36, 0x1b, 0x29, 0x3e,
+    0x22, 0x15, 0x1d, 0x0b, 0x2e, 0x08, 0x0c, 0x11,
+    0x2d, 0x2c, 0x3f, 0x0a, 0x0d, 0x19, 0x2b, 0x3e,
+    0x14, 0x2e, 0x32, 0x0f, 0x2e, 0x01, 0x07, 0x00,
+    0x24, 0x35, 0x1e, 0x2e, 0x05, 0x02, 0x26, 0x03,
+    0x37, 0x28, 0x33, 0x2a, 0x12, 0x17, 0x04, 0x1f,
+];
+
+static RP2C04_0003_PERMUTATION: [uint8_t, ..64] = [
+    0x14, 0x25, 0x3a, 0x10, 0x0b, 0x20, 0x31, 0x09,
+    0x01, 0x2e, 0x36, 0x08, 0x15, 0x3d, 0x3e, 0x3c,
+    0x22, 0x1c, 0x05, 0x12, 0x19, 0x18, 0x17, 0x1b,
+    0x00, 0x03, 0x2e, 0x02, 0x16, 0x06, 0x34, 0x35,
+    0x23, 0x0f, 0x0e, 0x37, 0x0d, 0x27, 0x26, 0x20,
+    0x29, 0x04, 0x21, 0x24, 0x11, 0x2d, 0x2e, 0x1f,
+    0x2c, 0x1e, 0x39, 0x33, 0x07, 0x2a, 0x28, 0x1d,
+    0x0a, 0x2e, 0x32, 0x38, 0x13, 0x2b, 0x3f, 0x0c,
+];
+
+static RP2C04_0004_PERMUTATION: [uint8_t, ..64] = [
+    0x18, 0x03, 0x1c, 0x28, 0x2e, 0x35, 0x01, 0x17,
+    0x10, 0x1f, 0x2a, 0x0e, 0x36, 0x37, 0x1a, 0x39,
+    0x25, 0x1e, 0x12, 0x34, 0x2e, 0x1d, 0x06, 0x26,
+    0x3e, 0x1b, 0x22, 0x19, 0x04, 0x2e, 0x3a, 0x21,
+    0x05, 0x0a, 0x07, 0x02, 0x13, 0x14, 0x00, 0x15,
+    0x0c, 0x3d, 0x11, 0x0f, 0x0d, 0x38, 0x2d, 0x24,
+    0x33, 0x20, 0x08, 0x16, 0x3f, 0x2b, 0x20, 0x3c,
+    0x2e, 0x27, 0x23, 0x31, 0x29, 0x32, 0x2c, 0x09,
+];
+
+// The television system the console was built for. This determines the colors the PPU
+// produces.
+#[deriving(Copy, PartialEq, Eq)]
+pub enum TvSystem {
+    Ntsc,
+    Pal,
+    Dendy,      // Famiclone PPU; its colors match the 2C07.
+}
+
+impl TvSystem {
+    // The time between frames on a real console: NTSC runs at about 60.10 Hz, PAL and Dendy at
+    // about 50.01 Hz.
+    pub fn frame_duration(self) -> Duration {
+        match self {
+            TvSystem::Ntsc => Duration::microseconds(16639),
+            TvSystem::Pal | TvSystem::Dendy => Duration::microseconds(19997),
+        }
+    }
+
+    fn palette(self) -> &'static [uint8_t, ..192] {
+        match self {
+            TvSystem::Ntsc => &NTSC_PALETTE,
+            TvSystem::Pal | TvSystem::Dendy => &PAL_PALETTE,
+        }
+    }
+}
+
+// The PPU chip in the system. The consumer NES uses the composite 2C02 (or 2C07 for PAL); the
+// PlayChoice-10 and VS System arcade boards use RGB variants.
+#[deriving(Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum PpuVariant {
+    Rp2C02,
+    Rp2C03,
+    Rp2C04_0001,
+    Rp2C04_0002,
+    Rp2C04_0003,
+    Rp2C04_0004,
+    Rp2C05_01,
+    Rp2C05_02,
+    Rp2C05_03,
+    Rp2C05_04,
+}
+
+impl PpuVariant {
+    fn palette(self, tv_system: TvSystem) -> &'static [uint8_t, ..192] {
+        match self {
+            PpuVariant::Rp2C02 => tv_system.palette(),
+            _ => &RGB_PALETTE,
+        }
+    }
+
+    // The order in which this PPU's palette indices map onto the colors of `palette`, if it
+    // isn't the natural one.
+    fn palette_permutation(self) -> Option<&'static [uint8_t, ..64]> {
+        match self {
+            PpuVariant::Rp2C04_0001 => Some(&RP2C04_0001_PERMUTATION),
+            PpuVariant::Rp2C04_0002 => Some(&RP2C04_0002_PERMUTATION),
+            PpuVariant::Rp2C04_0003 => Some(&RP2C04_0003_PERMUTATION),
+            PpuVariant::Rp2C04_0004 => Some(&RP2C04_0004_PERMUTATION),
+            _ => None,
+        }
+    }
+
+    // The 2C05 swaps the addresses of PPUCTRL and PPUMASK.
+    fn register(self, addr: uint16_t) -> uint16_t {
+        match (self.status_id().is_some(), addr & 7) {
+            (true, 0) => 1,
+            (true, 1) => 0,
+            (_, reg) => reg,
+        }
+    }
+
+    // The value the 2C05 places in bits 0-4 of PPUSTATUS so that games can identify it. Each
+    // revision has its own, and VS System games check for the one they were made for.
+    fn status_id(self) -> Option<uint8_t> {
+        match self {
+            PpuVariant::Rp2C05_01 | PpuVariant::Rp2C05_04 => Some(0x1b),
+            PpuVariant::Rp2C05_02 => Some(0x1d),
+            PpuVariant::Rp2C05_03 => Some(0x1c),
+            _ => None,
+        }
+    }
+}
+
 //
 // Registers
 //
@@ -53,9 +244,22 @@ struct Regs {
     oam_addr: uint8_t,       // OAMADDR: 0x2003
     scroll: PpuScroll,  // PPUSCROLL: 0x2005
     addr: PpuAddr,      // PPUADDR: 0x2006
+
+    // PPUSCROLL and PPUADDR share a single toggle that picks which half of the register a write
+    // goes to, so a PPUSCROLL write followed by a PPUADDR write sets the low byte of the address.
+    // Reading PPUSTATUS resets it.
+    write_toggle: WriteToggle,
+}
+
+save_struct!(Regs { ctrl, mask, status, oam_addr, scroll, addr, write_toggle });
+
+#[deriving(Copy, PartialEq, Eq)]
+enum WriteToggle {
+    First,      // X scroll, or the high byte of the address
+    Second,     // Y scroll, or the low byte of the address
 }
 
-save_struct!(Regs { ctrl, mask, status, oam_addr, scroll, addr });
+save_enum!(WriteToggle { First, Second });
 
 //
 // PPUCTRL: 0x2000
@@ -83,7 +287,7 @@ impl DerefMut<uint8_t> for PpuCtrl {
 
 impl PpuCtrl {
     fn x_scroll_offset(self) -> uint16_t               { if (*self & 0x01) == 0 { 0 } else { 256 } }
-    fn y_scroll_offset(self) -> uint16_t               { if (*self & 0x02) == 0 { 0 } else { 240 } }
+    fn y_scroll_offset(self) -> uint16_t               { if (*self & 0x02) == 0 { 0 } else { 256 } }
     fn vram_addr_increment(self) -> uint16_t           { if (*self & 0x04) == 0 { 1 } else { 32 } }
     fn sprite_pattern_table_addr(self) -> uint16_t     { if (*self & 0x08) == 0 { 0 } else { 0x1000 } }
     fn background_pattern_table_addr(self) -> uint16_t { if (*self & 0x10) == 0 { 0 } else { 0x1000 } }
@@ -116,14 +320,14 @@ impl DerefMut<uint8_t> for PpuMask {
 }
 
 impl PpuMask {
-    // 0x01: grayscale
-    // 0x02: show background on left
-    // 0x04: show sprites on left
+    fn grayscale(self) -> bool               { (*self & 0x01) != 0 }
+    fn show_background_left(self) -> bool    { (*self & 0x02) != 0 }
+    fn show_sprites_left(self) -> bool       { (*self & 0x04) != 0 }
     fn show_background(self) -> bool         { (*self & 0x08) != 0 }
     fn show_sprites(self) -> bool            { (*self & 0x10) != 0 }
-    // 0x20: intensify reds
-    // 0x40: intensify greens
-    // 0x80: intensify blues
+    fn emphasize_red(self) -> bool           { (*self & 0x20) != 0 }
+    fn emphasize_green(self) -> bool         { (*self & 0x40) != 0 }
+    fn emphasize_blue(self) -> bool          { (*self & 0x80) != 0 }
 }
 
 //
@@ -145,7 +349,9 @@ impl DerefMut<uint8_t> for PpuStatus {
 }
 
 impl PpuStatus {
-    // TODO: open bus junk in bits [0,5)
+    fn sprite_overflow(&self) -> bool   { (**self & 0x20) != 0 }
+    fn sprite_zero_hit(&self) -> bool   { (**self & 0x40) != 0 }
+    fn in_vblank(&self) -> bool         { (**self & 0x80) != 0 }
     fn set_sprite_overflow(&mut self, val: bool) {
         *self = if val { PpuStatus{ val: **self | 0x20 } }
         else { PpuStatus{ val: **self & !0x20} }
@@ -167,17 +373,9 @@ impl PpuStatus {
 struct PpuScroll {
     x: uint8_t,
     y: uint8_t,
-    next: PpuScrollDir
-}
-
-save_struct!(PpuScroll { x, y, next });
-
-enum PpuScrollDir {
-    X,
-    Y,
 }
 
-save_enum!(PpuScrollDir { X, Y });
+save_struct!(PpuScroll { x, y });
 
 //
 // PPUADDR: 0x2006
@@ -185,24 +383,37 @@ save_enum!(PpuScrollDir { X, Y });
 
 struct PpuAddr {
     val: uint16_t,
-    next: PpuAddrByte
 }
 
-save_struct!(PpuAddr { val, next });
+save_struct!(PpuAddr { val });
 
-enum PpuAddrByte {
-    Hi,
-    Lo,
+// Errors from PPU operations that a frontend or debugger may want to recover from.
+#[deriving(Copy, PartialEq, Eq)]
+pub enum PpuError {
+    InvalidVramAddress(uint16_t),   // Outside the PPU's 14-bit address space.
+    TimingStalled(uint16_t, uint16_t),  // The scanline and dot stopped advancing properly.
 }
 
-save_enum!(PpuAddrByte { Hi, Lo });
+impl fmt::Show for PpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PpuError::InvalidVramAddress(addr) => write!(f, "invalid VRAM address ${:04X}", addr),
+            PpuError::TimingStalled(scanline, dot) => {
+                write!(f, "PPU timing stalled at scanline {}, dot {}", scanline, dot)
+            }
+        }
+    }
+}
 
 // PPU VRAM. This implements the same Mem trait that the CPU memory does.
 
 pub struct Vram {
     pub mapper: Rc<RefCell<Box<Mapper+Send>>>,
-    pub nametables: [uint8_t, ..0x800],  // 2 nametables, 0x400 each. FIXME: Not correct for all mappers.
+    pub nametables: [uint8_t, ..0x800],  // 2 nametables, 0x400 each.
     pub palette: [uint8_t, ..0x20],
+    mirroring: Mirroring,                // The cartridge's wired mirroring. See `mirroring`.
+    palette_changed: bool,               // Set on palette writes, so the PPU can refresh caches.
+    stats: Option<VramStats>,            // None if access counting is disabled.
 }
 
 impl Vram {
@@ -210,40 +421,171 @@ impl Vram {
         Vram {
             mapper: mapper,
             nametables: [ 0, ..0x800 ],
-            palette: [ 0, ..0x20 ]
+            palette: [ 0, ..0x20 ],
+            mirroring: Mirroring::Vertical,
+            palette_changed: true,
+            stats: None,
+        }
+    }
+
+    // How the 4 nametable addresses map onto the 2 tables. Mappers such as MMC1 select this
+    // themselves and can change it at any time; otherwise it's the cartridge's wired mirroring.
+    pub fn mirroring(&self) -> Mirroring {
+        match self.mapper.borrow().mirroring() {
+            Some(mirroring) => mirroring,
+            None => self.mirroring,
+        }
+    }
+
+    // Sets the cartridge's wired mirroring, which applies unless the mapper selects its own. This
+    // takes effect from the next access on.
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.mirroring = mirroring;
+    }
+
+    // Maps an address in the nametable area to an index into `nametables`. In the single-screen
+    // modes, all four nametable addresses alias the same physical nametable.
+    #[inline(always)]
+    fn nametable_index(&self, addr: uint16_t) -> uint {
+        let table = (addr as uint >> 10) & 3;
+        let physical_table = match self.mirroring() {
+            Mirroring::Vertical => table & 1,
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::OneScreenLower => 0,
+            Mirroring::OneScreenUpper => 1,
+        };
+        physical_table * 0x400 + (addr as uint & 0x3ff)
+    }
+
+    // Enables or disables counting of VRAM accesses. Counting starts from zero when enabled.
+    pub fn set_stats_enabled(&mut self, enabled: bool) {
+        self.stats = if enabled { Some(VramStats::new()) } else { None };
+    }
+
+    // Returns the access counts so far, or None if counting is disabled.
+    pub fn stats(&self) -> Option<VramStats> {
+        self.stats
+    }
+
+    // Sets the access counts back to zero, if counting is enabled.
+    pub fn reset_stats(&mut self) {
+        if self.stats.is_some() {
+            self.stats = Some(VramStats::new());
+        }
+    }
+
+    #[inline(always)]
+    fn record_access(&mut self, addr: uint16_t, write: bool) {
+        match self.stats {
+            None => {}
+            Some(ref mut stats) => stats.record(addr, write),
         }
     }
+
+    // Maps an address in the palette area to an index into palette RAM. Entries 0x10, 0x14,
+    // 0x18, and 0x1c mirror the background entries 0x00, 0x04, 0x08, and 0x0c.
+    #[inline(always)]
+    fn palette_index(addr: uint16_t) -> uint {
+        let addr = addr as uint & 0x1f;
+        if (addr & 0x13) == 0x10 { addr & 0x0f } else { addr }
+    }
 }
 
-impl Mem for Vram {
+impl Vram {
+    // Reads pattern data ($0000-$1FFF) without counting the access, for tools that inspect the
+    // tiles rather than emulate a PPU fetch.
+    pub fn chr_loadb_untracked(&mut self, addr: uint16_t) -> uint8_t {
+        let mut mapper = self.mapper.borrow_mut();
+        mapper.chr_loadb(addr & 0x1fff)
+    }
+
+    // Like `loadb`, but returns an error for addresses outside VRAM instead of panicking.
     #[inline(always)]
-    fn loadb(&mut self, addr: uint16_t) -> uint8_t {
+    pub fn checked_loadb(&mut self, addr: uint16_t) -> Result<uint8_t,PpuError> {
+        self.record_access(addr, false);
         if addr < 0x2000 {          // Tilesets 0 or 1
             let mut mapper = self.mapper.borrow_mut();
-            mapper.chr_loadb(addr)
+            Ok(mapper.chr_loadb(addr))
         } else if addr < 0x3f00 {   // Name table area
-            self.nametables[addr as uint & 0x07ff]
+            Ok(self.nametables[self.nametable_index(addr)])
         } else if addr < 0x4000 {   // Palette area
-            self.palette[addr as uint & 0x1f]
+            Ok(self.palette[Vram::palette_index(addr)])
         } else {
-            panic!("invalid VRAM read")
+            Err(PpuError::InvalidVramAddress(addr))
+        }
+    }
+}
+
+impl Mem for Vram {
+    #[inline(always)]
+    fn loadb(&mut self, addr: uint16_t) -> uint8_t {
+        match self.checked_loadb(addr) {
+            Ok(val) => val,
+            Err(err) => panic!("{}", err),
         }
     }
     fn storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        self.record_access(addr, true);
         if addr < 0x2000 {
             let mut mapper = self.mapper.borrow_mut();
             mapper.chr_storeb(addr, val)
         } else if addr < 0x3f00 {           // Name table area
-            let addr = addr & 0x07ff;
-            self.nametables[addr as uint] = val;
+            let index = self.nametable_index(addr);
+            self.nametables[index] = val;
         } else if addr < 0x4000 {   // Palette area
-            let mut addr = addr & 0x1f;
-            if addr == 0x10 {
-                addr = 0x00;    // Mirror sprite background color into universal background color.
-            }
-            self.palette[addr as uint] = val;
+            // Palette RAM entries are only 6 bits wide.
+            self.palette[Vram::palette_index(addr)] = val & 0x3f;
+            self.palette_changed = true;
+        }
+    }
+}
+
+// Counts of VRAM reads and writes by region, for profiling how a game uses VRAM. Nametables are
+// numbered by address ($2000, $2400, $2800, $2C00), before mirroring.
+#[deriving(Copy, PartialEq, Eq, Show)]
+pub struct VramStats {
+    pub pattern_table_reads: [uint32_t, ..2],
+    pub pattern_table_writes: [uint32_t, ..2],
+    pub nametable_reads: [uint32_t, ..4],
+    pub nametable_writes: [uint32_t, ..4],
+    pub palette_reads: uint32_t,
+    pub palette_writes: uint32_t,
+}
+
+impl VramStats {
+    fn new() -> VramStats {
+        VramStats {
+            pattern_table_reads: [ 0, ..2 ],
+            pattern_table_writes: [ 0, ..2 ],
+            nametable_reads: [ 0, ..4 ],
+            nametable_writes: [ 0, ..4 ],
+            palette_reads: 0,
+            palette_writes: 0,
         }
     }
+
+    fn record(&mut self, addr: uint16_t, write: bool) {
+        let counter = if addr < 0x2000 {
+            let table = (addr >> 12) as uint;
+            if write {
+                &mut self.pattern_table_writes[table]
+            } else {
+                &mut self.pattern_table_reads[table]
+            }
+        } else if addr < 0x3f00 {
+            let nametable = ((addr >> 10) & 3) as uint;
+            if write {
+                &mut self.nametable_writes[nametable]
+            } else {
+                &mut self.nametable_reads[nametable]
+            }
+        } else if addr < 0x4000 {
+            if write { &mut self.palette_writes } else { &mut self.palette_reads }
+        } else {
+            return;
+        };
+        *counter += 1;
+    }
 }
 
 impl Save for Vram {
@@ -252,12 +594,15 @@ impl Save for Vram {
         nametables.save(fd);
         let mut palette: &mut [uint8_t] = self.palette.as_mut_slice();
         palette.save(fd);
+        self.mirroring.save(fd);
     }
     fn load(&mut self, fd: &mut File) {
         let mut nametables: &mut [uint8_t] = self.nametables.as_mut_slice();
         nametables.load(fd);
         let mut palette: &mut [uint8_t] = self.palette.as_mut_slice();
         palette.load(fd);
+        self.mirroring.load(fd);
+        self.palette_changed = true;
     }
 }
 
@@ -273,6 +618,15 @@ impl Oam {
     pub fn new() -> Oam {
         Oam { oam: [ 0, ..0x100 ] }
     }
+
+    pub fn as_bytes(&self) -> &[uint8_t, ..0x100] {
+        &self.oam
+    }
+
+    // Replaces all of OAM at once, for example to set up sprites for a test.
+    pub fn load_bytes(&mut self, data: &[uint8_t, ..0x100]) {
+        self.oam = *data;
+    }
 }
 
 impl Mem for Oam {
@@ -294,12 +648,13 @@ impl Save for Oam {
 #[deriving(Copy)]
 struct SpriteStruct {
     x: uint8_t,
-    y: uint8_t,
+    y: uint16_t,    // The first scanline the sprite is drawn on; see `make_sprite_info`.
     tile_index_byte: uint8_t,
     attribute_byte: uint8_t,
 }
 
-// Specifies the indices of the tiles that make up this sprite.
+// Specifies the indices of the tiles that make up this sprite. Tiles 0-255 are in the pattern
+// table at $0000 and 256-511 in the one at $1000.
 #[deriving(Copy)]
 enum SpriteTiles {
     Tiles8x8(uint16_t),
@@ -311,16 +666,19 @@ impl SpriteStruct {
         let base = ppu.regs.ctrl.sprite_pattern_table_addr();
         match ppu.regs.ctrl.sprite_size() {
             SpriteSize::Size8x8 => {
-                let addr = self.tile_index_byte as uint16_t | base;
+                let tile = self.tile_index_byte as uint16_t | (base >> 4);
 
-                SpriteTiles::Tiles8x8(addr)
+                SpriteTiles::Tiles8x8(tile)
             }
             SpriteSize::Size8x16 => {
-                // We ignore the base set in PPUCTRL here.
+                // We ignore the base set in PPUCTRL here; bit 0 of the tile index selects the
+                // pattern table instead.
                 let mut first = (self.tile_index_byte & !1) as uint16_t;
                 if (self.tile_index_byte & 1) != 0 {
-                    first += 0x1000;
+                    first += 0x100;
                 }
+                // `first` is even, so the bottom tile is always in the same pattern table: tile
+                // $FF draws tiles $FE and $FF of the table at $1000.
                 SpriteTiles::Tiles8x16(first, first + 1)
             }
         }
@@ -339,7 +697,7 @@ impl SpriteStruct {
     }
 
     // Quick test to see whether this sprite is on the given scanline.
-    fn on_scanline(&self, ppu: &Ppu, y: uint8_t) -> bool {
+    fn on_scanline(&self, ppu: &Ppu, y: uint16_t) -> bool {
         if y < self.y { return false; }
         match ppu.regs.ctrl.sprite_size() {
             SpriteSize::Size8x8  => y < self.y + 8,
@@ -347,9 +705,11 @@ impl SpriteStruct {
         }
     }
 
-    // Quick test to see whether the given point is in the bounding box of this sprite.
-    fn in_bounding_box(&self, ppu: &Ppu, x: uint8_t, y: uint8_t) -> bool {
-        x >= self.x && x < self.x + 8 && self.on_scanline(ppu, y)
+    // Quick test to see whether the given point is in the bounding box of this sprite. The
+    // right edge is computed in 16 bits: sprites at X=249 and beyond hang off the right side of
+    // the screen, and `self.x + 8` would wrap around in 8 bits and hide them entirely.
+    fn in_bounding_box(&self, ppu: &Ppu, x: uint8_t, y: uint16_t) -> bool {
+        x >= self.x && (x as uint16_t) < (self.x as uint16_t) + 8 && self.on_scanline(ppu, y)
     }
 }
 
@@ -361,38 +721,99 @@ pub struct Ppu {
     oam: Oam,
 
     pub screen: Box<[uint8_t, ..184320]>,  // 256 * 240 * 3
+    indexed_screen: Box<[uint8_t, ..61440]>,  // 256 * 240; the palette index of each pixel
+    layers: Box<[uint8_t, ..61440]>,        // 256 * 240; the layer each pixel came from
+    dirty_scanlines: [bool, ..240],         // Lines of `screen` changed since the last clear.
+    border_colors: [uint8_t, ..720],        // 240 * 3; the backdrop color of each line, in BGR
     scanline: uint16_t,
+    dot: uint16_t,          // 0-340 within the current scanline
+    nmi_pending: bool,      // An NMI was raised by a register write; reported by the next step.
     ppudata_buffer: uint8_t,
+    io_latch: uint8_t,      // The last value on the data bus between the CPU and the PPU.
 
     // NB: These two cannot always be computed from PPUCTRL and PPUSCROLL, because PPUADDR *also*
     // updates the scroll position. This is important to emulate.
+    //
+    // The high byte of each holds the nametable selected by PPUCTRL and the low byte the fine
+    // scroll from PPUSCROLL. Vertical scroll values of 240-255 are kept as they are; see
+    // `background_row`.
     scroll_x: uint16_t,
     scroll_y: uint16_t,
-
-    cy: uint64_t
+    // The nametable selected by PPUCTRL, in the same form as the high bytes above. Like the bits
+    // of the PPU's temporary address register that it models, it only reaches the scroll position
+    // when rendering reloads it: horizontally at the end of each scanline, and vertically at the
+    // end of the pre-render scanline.
+    next_nametable_x: uint16_t,
+    next_nametable_y: uint16_t,
+
+    tv_system: TvSystem,
+    variant: PpuVariant,
+    custom_palette: Option<Box<[uint8_t, ..192]>>,  // Overrides the variant's colors if set.
+    palette_overrides: [Option<uint8_t>, ..0x20],   // Forced palette RAM entries, for display.
+    palette_cache: [uint8_t, ..0x20],       // Palette RAM with overrides; see `load_palette`.
+    display_remap: Option<[uint8_t, ..64]>, // Display-only color mapping, if any.
+    color_cache: [Rgb, ..64],               // Displayed colors; see `cached_color`.
+    color_cache_mask: Option<uint8_t>,      // PPUMASK color bits of the cache, None if stale.
+    render_mode: RenderMode,
+
+    // The OAM indices of the sprites selected for the most recently evaluated scanline, in
+    // priority order. Normally at most 8, but see `max_sprites_per_line`.
+    visible_sprites: Vec<uint8_t>,
+    max_sprites_per_line: uint,
+    tile_replacer: Option<Box<TileReplacer+Send>>,  // Used by `render_frame_hd`, if set.
+    // The frontmost opaque sprite pixel at each X of that scanline, if any.
+    sprite_pixels: Box<[Option<SpritePixel>, ..256]>,
+    // The pattern addresses read by the sprite fetches at the end of the scanline.
+    sprite_fetch_addrs: [uint16_t, ..8],
+
+    oam_decay: bool,            // Whether OAM decays while rendering is disabled.
+    backdrop_hack: bool,        // Whether the backdrop follows the VRAM address; see `backdrop`.
+    oam_refresh_cy: uint64_t,   // The cycle at which rendering last refreshed OAM.
+
+    frame_count: uint64_t,
+    event_log: Option<Vec<PpuEvent>>,       // None if event logging is disabled.
+
+    scanline_breakpoint: Option<uint16_t>,  // `step` stops when it reaches this scanline.
+    render_stats: RenderStats,              // Work done so far in the current frame.
+    render_budget: Option<uint32_t>,        // The most scanlines to draw per frame, if limited.
+    last_frame_render_stats: RenderStats,   // Work done in the last complete frame.
+
+    // If the frame cache is on, `screen_dirty` is set by anything that could change the picture
+    // and cleared at the start of each frame, when it moves into `frame_dirty`. Scanlines are only
+    // drawn while either flag is set, and `render_frame` skips rendering while both are clear.
+    frame_cache: bool,
+    screen_dirty: bool,
+    frame_dirty: bool,
+
+    // The CPU cycle the current scanline started on, and how many dots into that cycle it
+    // started. Scanlines aren't a whole number of CPU cycles long.
+    cy: uint64_t,
+    cy_dots: uint64_t,
+    dot_skipped: bool,      // The odd-frame dot skip happened on this tick; see `tick_prerender`.
 }
 
 impl Mem for Ppu {
     // Performs a load of the PPU register at the given CPU address.
     fn loadb(&mut self, addr: uint16_t) -> uint8_t {
         debug_assert(addr >= 0x2000 && addr < 0x4000, "invalid PPU register");
-        match addr & 7 {
-            0 => *self.regs.ctrl,
-            1 => *self.regs.mask,
+        let val = match self.variant.register(addr) {
+            // Reading a write-only register returns whatever is left on the PPU's data bus.
+            0 | 1 | 3 | 5 | 6 => self.io_latch,
             2 => self.read_ppustatus(),
-            3 => 0, // OAMADDR is read-only
-            4 => panic!("OAM read unimplemented"),
-            5 => 0, // PPUSCROLL is read-only
-            6 => 0, // PPUADDR is read-only
+            4 => self.read_oamdata(),
             7 => self.read_ppudata(),
-            _ => panic!("can't happen")
-        }
+            _ => unreachable!()
+        };
+        self.io_latch = val;
+        val
     }
 
     // Performs a store to the PPU register at the given CPU address.
     fn storeb(&mut self, addr: uint16_t, val: uint8_t) {
         debug_assert(addr >= 0x2000 && addr < 0x4000, "invalid PPU register");
-        match addr & 7 {
+        self.io_latch = val;
+        self.screen_dirty = true;
+        match self.variant.register(addr) {
             0 => self.update_ppuctrl(val),
             1 => self.regs.mask = PpuMask{val: val},
             2 => (),    // PPUSTATUS is read-only
@@ -401,16 +822,94 @@ impl Mem for Ppu {
             5 => self.update_ppuscroll(val),
             6 => self.update_ppuaddr(val),
             7 => self.write_ppudata(val),
-            _ => panic!("can't happen")
+            _ => unreachable!()
         }
     }
 }
 
+// The interrupts raised during a step, so that the CPU loop can handle all of them the same way.
+#[deriving(PartialEq, Eq, Copy)]
+pub struct InterruptFlags { val: uint8_t }
+
+impl Deref<uint8_t> for InterruptFlags {
+    fn deref(&self) -> &uint8_t {
+        &self.val
+    }
+}
+
+impl InterruptFlags {
+    pub fn vblank_nmi(self) -> bool { (*self & 0x01) != 0 }    // We entered VBLANK.
+    pub fn mapper_irq(self) -> bool { (*self & 0x02) != 0 }    // The mapper's scanline IRQ.
+    fn set_vblank_nmi(&mut self)    { self.val |= 0x01 }
+    fn set_mapper_irq(&mut self)    { self.val |= 0x02 }
+}
+
 #[deriving(PartialEq, Eq, Copy)]
 pub struct StepResult {
     pub new_frame: bool,    // We wrapped around to the next scanline.
-    pub vblank_nmi: bool,   // We entered VBLANK and must generate an NMI.
-    pub scanline_irq: bool, // The mapper wants to execute a scanline IRQ.
+    pub interrupts: InterruptFlags,
+    // The CPU cycle at which the vblank NMI was raised, if it was, so that the CPU can delay
+    // taking it until then instead of taking it as soon as `step` returns.
+    pub nmi_cycle: Option<uint64_t>,
+    pub breakpoint_hit: bool,   // We stopped at the scanline breakpoint.
+    // Set if `step` stopped because the timing counters went wrong; see `check_timing`.
+    pub timing_error: Option<PpuError>,
+}
+
+impl StepResult {
+    fn new() -> StepResult {
+        StepResult {
+            new_frame: false,
+            interrupts: InterruptFlags { val: 0 },
+            nmi_cycle: None,
+            breakpoint_hit: false,
+            timing_error: None,
+        }
+    }
+
+    fn merge(&mut self, other: StepResult) {
+        self.new_frame = self.new_frame || other.new_frame;
+        self.breakpoint_hit = self.breakpoint_hit || other.breakpoint_hit;
+        self.timing_error = self.timing_error.or(other.timing_error);
+        self.interrupts.val |= other.interrupts.val;
+        self.nmi_cycle = match (self.nmi_cycle, other.nmi_cycle) {
+            (Some(a), Some(b)) => Some(cmp::min(a, b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    // Raises the vblank NMI at the given CPU cycle. If it was already raised, the earlier cycle
+    // wins.
+    fn raise_nmi(&mut self, cy: uint64_t) {
+        self.interrupts.set_vblank_nmi();
+        if self.nmi_cycle.map_or(true, |nmi_cycle| cy < nmi_cycle) {
+            self.nmi_cycle = Some(cy);
+        }
+    }
+}
+
+// Notable things that happened inside the PPU, recorded when event logging is enabled.
+#[deriving(Copy, PartialEq, Eq, Show)]
+pub enum PpuEvent {
+    VblankStart(uint64_t),                                  // CPU cycle at which vblank began
+    SpriteZeroHit { scanline: uint16_t, x: uint8_t },       // First sprite 0 hit this frame
+    SpriteOverflow(uint16_t),                               // Scanline that overflowed
+    FrameComplete(uint64_t),                                // Number of frames completed so far
+}
+
+// A layer of the picture that can be captured on its own; see `Ppu::screenshot_layer`.
+#[deriving(Copy, PartialEq, Eq, Show)]
+pub enum Layer {
+    Background,
+    Sprites,
+}
+
+// How the PPU renders. `Fast` renders each scanline in one go when the scanline begins;
+// `Accurate` renders dot by dot, so mid-scanline register changes are visible.
+#[deriving(Copy, PartialEq, Eq)]
+pub enum RenderMode {
+    Fast,
+    Accurate,
 }
 
 #[deriving(Copy)]
@@ -420,12 +919,88 @@ struct Rgb {
     b: uint8_t,
 }
 
+// Works out the color a palette index is displayed as under the given PPUMASK. The steps are:
+// grayscale, which keeps only the luminance bits of the index; `remap`, a display-only mapping
+// of the index the PPU outputs; `permutation`, the 2C04's scrambled palette order; looking the
+// color up in `palette` (64 RGB triples); and emphasis. Like the screen, the result is in BGR
+// order: `r` holds blue and `b` holds red.
+fn resolve_color(palette_index: uint8_t,
+                 mask: PpuMask,
+                 remap: Option<&[uint8_t, ..64]>,
+                 permutation: Option<&[uint8_t, ..64]>,
+                 palette: &[uint8_t, ..192])
+                 -> Rgb {
+    let mut palette_index = palette_index & 0x3f;
+    if mask.grayscale() {
+        palette_index &= 0x30;
+    }
+    match remap {
+        Some(remap) => palette_index = remap[palette_index as uint] & 0x3f,
+        None => {}
+    }
+    match permutation {
+        Some(permutation) => palette_index = permutation[palette_index as uint] & 0x3f,
+        None => {}
+    }
+
+    let offset = palette_index as uint * 3;
+    let mut red = palette[offset];
+    let mut green = palette[offset + 1];
+    let mut blue = palette[offset + 2];
+
+    // Each emphasis bit makes the PPU attenuate the other two channels of the video signal. In
+    // RGB this comes out as those channels dropping to about 80%; 13/16 is close enough and
+    // cheap. Emphasized channels are left as they are.
+    if *mask & 0xe0 != 0 {
+        let attenuate = |c: uint8_t| (c as uint16_t * 13 / 16) as uint8_t;
+        if !mask.emphasize_red() { red = attenuate(red); }
+        if !mask.emphasize_green() { green = attenuate(green); }
+        if !mask.emphasize_blue() { blue = attenuate(blue); }
+    }
+
+    Rgb { r: blue, g: green, b: red }
+}
+
 #[deriving(Copy)]
 enum PatternPixelKind {
     Background,
     Sprite,
 }
 
+// The per-frame state that drawing scanlines updates as it goes, besides the screen buffers.
+// Paths that redraw the whole frame outside of `step` save this first and put it back after, so
+// that the redraw leaves no trace on the frame being emulated.
+struct RenderState {
+    scanline: uint16_t,
+    status: uint8_t,
+    visible_sprites: Vec<uint8_t>,
+    sprite_pixels: Box<[Option<SpritePixel>, ..256]>,
+    render_stats: RenderStats,
+    border_colors: [uint8_t, ..720],
+    dirty_scanlines: [bool, ..240],
+    event_count: uint,          // Length of the event log, if logging is enabled.
+}
+
+// The tile pixel behind a screen pixel, for `Ppu::render_frame_hd`.
+#[deriving(Copy)]
+struct HdTileSource {
+    tile: uint16_t,         // 0-511, including the pattern table
+    palette: uint8_t,       // Palette row (0-7)
+    x: uint8_t,             // Position within the tile, before flipping
+    y: uint8_t,
+    flip_horizontal: bool,
+    flip_vertical: bool,
+}
+
+// The background fetched for one screen pixel.
+#[deriving(Copy)]
+struct BackgroundPixel {
+    tile_addr: uint16_t,    // Address of the tile's nametable entry
+    tile: uint8_t,
+    palette: uint8_t,       // Palette row (0-3) selected by the attribute table
+    pattern_color: uint8_t, // 0 is transparent
+}
+
 #[deriving(Copy)]
 struct NametableAddr {
     base: uint16_t,
@@ -436,7 +1011,15 @@ struct NametableAddr {
 #[deriving(Copy)]
 struct SpriteColor {
     priority: SpritePriority,
-    color: Rgb,
+    palette_index: uint8_t,
+}
+
+// A sprite pixel latched during sprite evaluation.
+#[deriving(Copy)]
+struct SpritePixel {
+    index: uint8_t,             // OAM index of the sprite
+    priority: SpritePriority,
+    tile_color: uint8_t,        // Palette row and pattern color, before the palette lookup
 }
 
 #[deriving(Copy)]
@@ -445,26 +1028,196 @@ enum SpritePriority {
     BelowBg,
 }
 
+// Counts of the rendering work done during one frame. Useful as a cheap proxy for the time spent
+// in the renderer.
+#[deriving(Copy, PartialEq, Eq, Show)]
+pub struct RenderStats {
+    pub scanlines_rendered: uint32_t,
+    pub pixels_drawn: uint32_t,
+    pub max_sprites_per_line: uint8_t,  // Sprites in range on the busiest scanline, uncapped.
+}
+
+// A copy of the screen that can be handed to another thread, for example to present it without
+// holding on to the PPU.
+#[deriving(Clone)]
+pub struct FrameSnapshot {
+    pub frame_count: uint64_t,
+    pub screen: Vec<uint8_t>,       // 256 * 240 * 3, in the same layout as `Ppu::screen`
+}
+
+// A high-resolution replacement for an 8x8 tile, for texture packs: `8 * scale` by `8 * scale`
+// RGB pixels.
+pub struct HdTile {
+    pub scale: uint,
+    pub pixels: Vec<uint8_t>,
+}
+
+// Supplies replacement tiles for `Ppu::render_frame_hd`. Tiles are identified by a hash of
+// their 16 bytes of pattern data (see `Ppu::chr_hash`) and the 4 RGB colors they are drawn
+// with, so the same graphics are found wherever the game puts them in CHR memory.
+pub trait TileReplacer {
+    fn replace(&self, chr_hash: uint64_t, palette: [[uint8_t, ..3], ..4]) -> Option<&HdTile>;
+}
+
+// What produced a given screen pixel, for debuggers.
+#[deriving(Copy, PartialEq, Eq, Show)]
+pub struct PixelInfo {
+    pub nametable_addr: uint16_t,   // Address of the tile's nametable entry
+    pub tile_index: uint8_t,
+    pub palette: uint8_t,           // Background palette row (0-3) from the attribute table
+    pub pattern_color: uint8_t,     // Background pattern color (0-3); 0 is transparent
+    pub sprite: Option<uint8_t>,    // OAM index of the frontmost opaque sprite covering the pixel
+}
+
+impl RenderStats {
+    fn new() -> RenderStats {
+        RenderStats { scanlines_rendered: 0, pixels_drawn: 0, max_sprites_per_line: 0 }
+    }
+}
+
 impl Save for Ppu {
     fn save(&mut self, fd: &mut File) {
         self.regs.save(fd);
         self.vram.save(fd);
         self.oam.save(fd);
         self.scanline.save(fd);
+        self.dot.save(fd);
         self.ppudata_buffer.save(fd);
         self.scroll_x.save(fd);
         self.scroll_y.save(fd);
+        self.next_nametable_x.save(fd);
+        self.next_nametable_y.save(fd);
         self.cy.save(fd);
+        self.cy_dots.save(fd);
+        self.frame_count.save(fd);
     }
     fn load(&mut self, fd: &mut File) {
         self.regs.load(fd);
         self.vram.load(fd);
         self.oam.load(fd);
         self.scanline.load(fd);
+        self.dot.load(fd);
         self.ppudata_buffer.load(fd);
         self.scroll_x.load(fd);
         self.scroll_y.load(fd);
+        self.next_nametable_x.load(fd);
+        self.next_nametable_y.load(fd);
         self.cy.load(fd);
+        self.cy_dots.load(fd);
+        self.frame_count.load(fd);
+        self.oam_refresh_cy = self.cy;
+        self.screen_dirty = true;
+
+        // If the state was saved partway through a visible scanline, redo that scanline's sprite
+        // selection so that the rest of it renders with the same sprites. The status flags were
+        // already saved, so keep them as they are.
+        if self.dot > 0 && self.scanline < (SCREEN_HEIGHT as uint16_t) {
+            let status = *self.regs.status;
+            self.compute_visible_sprites();
+            self.regs.status = PpuStatus { val: status };
+        }
+    }
+}
+
+// Collects the options for a new PPU. The defaults are the same as those of `Ppu::new`.
+pub struct PpuBuilder {
+    tv_system: TvSystem,
+    variant: PpuVariant,
+    palette: Option<Box<[uint8_t, ..192]>>,
+    render_mode: RenderMode,
+    sprite_limit: uint,
+    noise_seed: Option<uint64_t>,
+    framebuffer: Option<Box<[uint8_t, ..184320]>>,
+    clear_color: Option<(uint8_t, uint8_t, uint8_t)>,
+}
+
+impl PpuBuilder {
+    pub fn new() -> PpuBuilder {
+        PpuBuilder {
+            tv_system: TvSystem::Ntsc,
+            variant: PpuVariant::Rp2C02,
+            palette: None,
+            render_mode: RenderMode::Fast,
+            sprite_limit: 8,
+            noise_seed: None,
+            framebuffer: None,
+            clear_color: None,
+        }
+    }
+
+    pub fn tv_system(mut self, tv_system: TvSystem) -> PpuBuilder {
+        self.tv_system = tv_system;
+        self
+    }
+
+    pub fn variant(mut self, variant: PpuVariant) -> PpuBuilder {
+        self.variant = variant;
+        self
+    }
+
+    // See `Ppu::set_palette`.
+    pub fn palette(mut self, palette: Box<[uint8_t, ..192]>) -> PpuBuilder {
+        self.palette = Some(palette);
+        self
+    }
+
+    pub fn render_mode(mut self, render_mode: RenderMode) -> PpuBuilder {
+        self.render_mode = render_mode;
+        self
+    }
+
+    // See `Ppu::set_max_sprites_per_line`.
+    pub fn sprite_limit(mut self, limit: uint) -> PpuBuilder {
+        self.sprite_limit = limit;
+        self
+    }
+
+    // Fills OAM and the nametables with noise from the given seed; see `Ppu::new_with_noise`.
+    pub fn noise_seed(mut self, seed: uint64_t) -> PpuBuilder {
+        self.noise_seed = Some(seed);
+        self
+    }
+
+    // Renders into the given buffer instead of allocating a new one. Its contents are kept until
+    // they are drawn over.
+    pub fn framebuffer(mut self, framebuffer: Box<[uint8_t, ..184320]>) -> PpuBuilder {
+        self.framebuffer = Some(framebuffer);
+        self
+    }
+
+    // Fills the screen with the given RGB color before anything is rendered, so that pixels that
+    // never get drawn stand out from black ones.
+    pub fn clear_color(mut self, r: uint8_t, g: uint8_t, b: uint8_t) -> PpuBuilder {
+        self.clear_color = Some((r, g, b));
+        self
+    }
+
+    pub fn build(self, vram: Vram, oam: Oam) -> Ppu {
+        let mut ppu = match self.noise_seed {
+            Some(seed) => Ppu::new_with_noise(vram, oam, seed),
+            None => Ppu::new(vram, oam),
+        };
+        ppu.set_tv_system(self.tv_system);
+        ppu.set_variant(self.variant);
+        ppu.set_palette(self.palette);
+        ppu.set_render_mode(self.render_mode);
+        ppu.set_max_sprites_per_line(self.sprite_limit);
+        match self.framebuffer {
+            Some(framebuffer) => ppu.screen = framebuffer,
+            None => {}
+        }
+        match self.clear_color {
+            Some((r, g, b)) => {
+                // The screen is stored in BGR order.
+                for pixel in ppu.screen.as_mut_slice().chunks_mut(3) {
+                    pixel[0] = b;
+                    pixel[1] = g;
+                    pixel[2] = r;
+                }
+            }
+            None => {}
+        }
+        ppu
     }
 }
 
@@ -476,119 +1229,926 @@ impl Ppu {
                 mask: PpuMask{val: 0},
                 status: PpuStatus{val:0},
                 oam_addr: 0,
-                scroll: PpuScroll { x: 0, y: 0, next: PpuScrollDir::X },
-                addr: PpuAddr { val: 0, next: PpuAddrByte::Hi },
+                scroll: PpuScroll { x: 0, y: 0 },
+                addr: PpuAddr { val: 0 },
+                write_toggle: WriteToggle::First,
             },
             vram: vram,
             oam: oam,
 
             screen: box() ([ 0, ..184320 ]),
+            indexed_screen: box() ([ 0, ..61440 ]),
+            layers: box() ([ LAYER_BACKDROP, ..61440 ]),
+            dirty_scanlines: [ false, ..240 ],
+            border_colors: [ 0, ..720 ],
             scanline: 0,
+            dot: 0,
+            nmi_pending: false,
             ppudata_buffer: 0,
+            io_latch: 0,
 
             scroll_x: 0,
             scroll_y: 0,
-
-            cy: 0
+            next_nametable_x: 0,
+            next_nametable_y: 0,
+
+            tv_system: TvSystem::Ntsc,
+            variant: PpuVariant::Rp2C02,
+            custom_palette: None,
+            palette_overrides: [ None, ..0x20 ],
+            palette_cache: [ 0, ..0x20 ],
+            display_remap: None,
+            color_cache: [ Rgb { r: 0, g: 0, b: 0 }, ..64 ],
+            color_cache_mask: None,
+            render_mode: RenderMode::Fast,
+
+            visible_sprites: Vec::with_capacity(64),
+            max_sprites_per_line: 8,
+            tile_replacer: None,
+            sprite_pixels: box() ([None, ..256]),
+            sprite_fetch_addrs: [ 0, ..8 ],
+
+            oam_decay: false,
+            backdrop_hack: false,
+            oam_refresh_cy: 0,
+
+            frame_count: 0,
+            event_log: None,
+
+            scanline_breakpoint: None,
+            render_stats: RenderStats::new(),
+            render_budget: None,
+            last_frame_render_stats: RenderStats::new(),
+
+            frame_cache: false,
+            screen_dirty: true,
+            frame_dirty: true,
+
+            cy: 0,
+            cy_dots: 0,
+            dot_skipped: false,
         }
     }
 
-    //
-    // Color utilities
-    //
-
-    #[inline(always)]
-    fn get_color(&self, palette_index: uint8_t) -> Rgb {
-        Rgb {
-            r: PALETTE[palette_index as uint * 3 + 2],
-            g: PALETTE[palette_index as uint * 3 + 1],
-            b: PALETTE[palette_index as uint * 3 + 0],
+    // Like `new`, but fills OAM and the nametables with a pseudo-random pattern, approximating
+    // the garbage left in uninitialized RAM at power-on. The same seed always produces the same
+    // contents, so runs stay reproducible.
+    pub fn new_with_noise(mut vram: Vram, mut oam: Oam, seed: uint64_t) -> Ppu {
+        let mut rng = Xorshift::with_seed(seed);
+        for byte in oam.oam.iter_mut() {
+            *byte = rng.next() as uint8_t;
         }
+        for byte in vram.nametables.iter_mut() {
+            *byte = rng.next() as uint8_t;
+        }
+        Ppu::new(vram, oam)
     }
 
-    //
-    // Register manipulation
-    //
+    pub fn tv_system(&self) -> TvSystem {
+        self.tv_system
+    }
 
-    fn update_ppuctrl(&mut self, val: uint8_t) {
-        self.regs.ctrl = PpuCtrl{val:val};
+    pub fn set_tv_system(&mut self, tv_system: TvSystem) {
+        self.tv_system = tv_system;
+        self.color_cache_mask = None;
+        self.screen_dirty = true;
+    }
 
-        self.scroll_x = (self.scroll_x & 0xff) | self.regs.ctrl.x_scroll_offset();
-        self.scroll_y = (self.scroll_y & 0xff) | self.regs.ctrl.y_scroll_offset();
+    // How long each frame should be presented for to run at the speed of the TV system.
+    pub fn frame_duration(&self) -> Duration {
+        self.tv_system.frame_duration()
     }
 
-    fn update_ppuscroll(&mut self, val: uint8_t) {
-        match self.regs.scroll.next {
-            PpuScrollDir::X => {
-                self.scroll_x = (self.scroll_x & 0xff00) | (val as uint16_t);
+    // The number of whole CPU cycles in a frame as this PPU runs it, whatever the TV system:
+    // 262 scanlines of `DOTS_PER_SCANLINE` dots. Frames are really 29780 2/3 cycles long, less a
+    // dot on odd frames with rendering enabled, so they take 29780 or 29781 whole cycles.
+    pub fn cycles_per_frame(&self) -> uint64_t {
+        (LAST_SCANLINE as uint64_t + 1) * (DOTS_PER_SCANLINE as uint64_t) / DOTS_PER_CPU_CYCLE
+    }
 
-                self.regs.scroll.x = val;
-                self.regs.scroll.next = PpuScrollDir::Y;
-            }
-            PpuScrollDir::Y => {
-                self.scroll_y = (self.scroll_y & 0xff00) | (val as uint16_t);
+    // See `Vram::set_mirroring`.
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.vram.set_mirroring(mirroring);
+        self.screen_dirty = true;
+    }
 
-                self.regs.scroll.y = val;
-                self.regs.scroll.next = PpuScrollDir::X;
-            }
+    // Sets all 960 tile entries of nametable `which` (0-3, for $2000-$2C00) to `tile`, leaving
+    // its attribute table alone. With mirroring, this also fills the nametable it aliases. Much
+    // quicker than going through PPUDATA when setting up a test scene.
+    pub fn fill_nametable(&mut self, which: uint8_t, tile: uint8_t) {
+        assert!(which < 4, "nametable must be between 0 and 3");
+
+        let start = self.vram.nametable_index(0x2000 + (which as uint16_t) * 0x400);
+        for entry in self.vram.nametables.slice_mut(start, start + 0x3c0).iter_mut() {
+            *entry = tile;
         }
+        self.screen_dirty = true;
     }
 
-    fn write_oamdata(&mut self, val: uint8_t) {
-        self.oam.storeb(self.regs.oam_addr as uint16_t, val);
-        self.regs.oam_addr += 1;
+    pub fn scanline(&self) -> uint16_t {
+        self.scanline
     }
 
-    fn update_ppuaddr(&mut self, val: uint8_t) {
-        match self.regs.addr.next {
-            PpuAddrByte::Hi => {
-                self.regs.addr.val = (self.regs.addr.val & 0x00ff) | ((val as uint16_t) << 8);
-                self.regs.addr.next = PpuAddrByte::Lo;
-            }
-            PpuAddrByte::Lo => {
-                self.regs.addr.val = (self.regs.addr.val & 0xff00) | (val as uint16_t);
-                self.regs.addr.next = PpuAddrByte::Hi;
+    // The current dot (0-340) within the scanline. In fast mode, `step` runs whole scanlines, so
+    // this stays at 0 unless the PPU is advanced with `tick`.
+    pub fn dot(&self) -> uint16_t {
+        self.dot
+    }
 
-                // Adjust the scroll registers.
-                // TODO: This is pretty much a hack. The right way is to precisely emulate the PPU
-                // internal registers.
-                // TODO: Y scrolling.
-                let addr = self.regs.addr.val & 0x07ff;
-                let xscroll_base = if addr < 0x400 { 0 } else { 256 };
-                self.scroll_x = (self.scroll_x & 0xff) | xscroll_base;
-            }
-        }
+    pub fn variant(&self) -> PpuVariant {
+        self.variant
     }
 
-    fn read_ppustatus(&mut self) -> uint8_t {
-        // Reset latch.
-        self.regs.scroll.next = PpuScrollDir::X;
-        self.regs.addr.next = PpuAddrByte::Hi;
+    pub fn set_variant(&mut self, variant: PpuVariant) {
+        self.variant = variant;
+        self.color_cache_mask = None;
+        self.screen_dirty = true;
+    }
 
-        *self.regs.status
+    // Replaces the colors of the PPU variant with the given 64 RGB triples, or restores them if
+    // `None`.
+    pub fn set_palette(&mut self, palette: Option<Box<[uint8_t, ..192]>>) {
+        self.custom_palette = palette;
+        self.color_cache_mask = None;
+        self.screen_dirty = true;
     }
 
-    fn write_ppudata(&mut self, val: uint8_t) {
-        self.vram.storeb(self.regs.addr.val, val);
-        self.regs.addr.val += self.regs.ctrl.vram_addr_increment();
+    pub fn render_mode(&self) -> RenderMode {
+        self.render_mode
     }
 
-    fn read_ppudata(&mut self) -> uint8_t {
-        let addr = self.regs.addr.val;
-        let val = self.vram.loadb(addr);
-        self.regs.addr.val += self.regs.ctrl.vram_addr_increment();
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode;
+    }
 
-        // Emulate the PPU buffering quirk.
-        if addr < 0x3f00 {
-            let buffered_val = self.ppudata_buffer;
-            self.ppudata_buffer = val;
-            buffered_val
-        } else {
-            val
-        }
+    // Limits how many scanlines are drawn per frame, to bound the time spent rendering. Lines
+    // past the budget keep what was drawn on them before, but still set the sprite flags, so
+    // games behave the same. `None` removes the limit.
+    pub fn set_render_budget(&mut self, budget: Option<uint32_t>) {
+        self.render_budget = budget;
     }
 
-    //
+    // Turns the frame cache on or off. With it on, `step` stops drawing once a whole frame has
+    // gone by without anything that could change the picture: no PPU register writes (which
+    // includes VRAM and OAM writes), no mapper writes (see `invalidate_frame_cache`), and no
+    // changes to the PPU's settings. The screen keeps the last frame drawn, and `render_frame`
+    // returns it as it is. Drawing resumes with the first such change. This saves a lot of work
+    // on static title screens and menus.
+    pub fn set_frame_cache_enabled(&mut self, enabled: bool) {
+        self.frame_cache = enabled;
+        self.screen_dirty = true;
+    }
+
+    // Forces the PPU to draw again. Mapper writes must call this, since switching CHR banks
+    // changes the picture without going through the PPU.
+    pub fn invalidate_frame_cache(&mut self) {
+        self.screen_dirty = true;
+    }
+
+    // Makes `step` return as soon as the PPU reaches the start of the given scanline (0-261),
+    // with `breakpoint_hit` set, so that a debugger can look at the PPU partway through a frame.
+    // `None` removes the breakpoint.
+    pub fn set_scanline_breakpoint(&mut self, scanline: Option<uint16_t>) {
+        self.scanline_breakpoint = scanline;
+    }
+
+    pub fn oam(&self) -> &Oam {
+        &self.oam
+    }
+
+    pub fn oam_mut(&mut self) -> &mut Oam {
+        self.screen_dirty = true;
+        &mut self.oam
+    }
+
+    pub fn last_frame_render_stats(&self) -> RenderStats {
+        self.last_frame_render_stats
+    }
+
+    // Returns the most sprites found on any one scanline during the last frame, including those
+    // past the per-line limit that weren't drawn. Above 8 means the game is flickering or losing
+    // sprites on real hardware.
+    pub fn last_frame_max_sprites_per_line(&self) -> uint8_t {
+        self.last_frame_render_stats.max_sprites_per_line
+    }
+
+    // Returns the number of frames completed since power-on.
+    pub fn frame_count(&self) -> uint64_t {
+        self.frame_count
+    }
+
+    //
+    // Event logging
+    //
+
+    pub fn set_event_logging(&mut self, enabled: bool) {
+        self.event_log = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    // Returns the events logged since the last call, oldest first.
+    pub fn take_events(&mut self) -> Vec<PpuEvent> {
+        match self.event_log {
+            Some(ref mut log) => mem::replace(log, Vec::new()),
+            None => Vec::new(),
+        }
+    }
+
+    fn log_event(&mut self, event: PpuEvent) {
+        match self.event_log {
+            Some(ref mut log) => log.push(event),
+            None => {}
+        }
+    }
+
+    //
+    // VRAM access counting
+    //
+
+    pub fn set_vram_stats_enabled(&mut self, enabled: bool) {
+        self.vram.set_stats_enabled(enabled);
+    }
+
+    pub fn vram_stats(&self) -> Option<VramStats> {
+        self.vram.stats()
+    }
+
+    pub fn reset_vram_stats(&mut self) {
+        self.vram.reset_stats();
+    }
+
+    // Copies the screen and the current frame count into a snapshot.
+    pub fn snapshot_frame(&self) -> FrameSnapshot {
+        FrameSnapshot {
+            frame_count: self.frame_count,
+            screen: self.screen.to_vec(),
+        }
+    }
+
+    // Returns which lines of the screen have changed since `clear_dirty_scanlines` was last
+    // called, so that displays which are slow to update can redraw only those.
+    pub fn dirty_scanlines(&self) -> &[bool, ..240] {
+        &self.dirty_scanlines
+    }
+
+    pub fn clear_dirty_scanlines(&mut self) {
+        self.dirty_scanlines = [ false, ..240 ];
+    }
+
+    // Returns the RGB color of the border beside the given visible scanline. On a TV the border
+    // shows the backdrop color, so this is the backdrop as it was when the scanline was drawn, and
+    // games that change the backdrop partway through the frame change the border at that line
+    // too. Frontends that draw the overscan area can use this to match the picture.
+    pub fn border_color(&self, scanline: uint) -> [uint8_t, ..3] {
+        assert!(scanline < SCREEN_HEIGHT, "scanline must be below 240");
+
+        // The colors are stored in BGR order, like the screen.
+        let offset = scanline * 3;
+        let colors = &self.border_colors;
+        [ colors[offset + 2], colors[offset + 1], colors[offset + 0] ]
+    }
+
+    // Copies the screen into `out` as RGBA, with an alpha of zero wherever neither the
+    // background nor a sprite was drawn (that is, where the backdrop color shows through).
+    pub fn copy_rgba_with_transparency(&mut self, out: &mut [uint8_t]) {
+        assert!(out.len() == SCREEN_WIDTH * SCREEN_HEIGHT * 4, "RGBA buffer has the wrong size");
+        for i in range(0, SCREEN_WIDTH * SCREEN_HEIGHT) {
+            // The screen is stored in BGR order.
+            out[i * 4 + 0] = self.screen[i * 3 + 2];
+            out[i * 4 + 1] = self.screen[i * 3 + 1];
+            out[i * 4 + 2] = self.screen[i * 3 + 0];
+            out[i * 4 + 3] = if self.layers[i] == LAYER_BACKDROP { 0 } else { 255 };
+        }
+    }
+
+    // Like `copy_rgba_with_transparency`, but with the color premultiplied by alpha, so that
+    // transparent pixels come out as (0, 0, 0, 0).
+    pub fn copy_rgba_premultiplied(&mut self, out: &mut [uint8_t]) {
+        self.copy_rgba_with_transparency(out);
+        for pixel in out.chunks_mut(4) {
+            if pixel[3] == 0 {
+                pixel[0] = 0;
+                pixel[1] = 0;
+                pixel[2] = 0;
+            }
+        }
+    }
+
+    // Returns the OAM indices of the sprites that were selected for the most recently rendered
+    // scanline, in priority order.
+    pub fn last_scanline_sprites(&self) -> [Option<uint8_t>, ..8] {
+        let mut result = [None, ..8];
+        for (slot, &index) in result.iter_mut().zip(self.visible_sprites.iter()) {
+            *slot = Some(index);
+        }
+        result
+    }
+
+    // Sets how many sprites are drawn on each scanline. The hardware draws 8, which makes
+    // sprites flicker when games cycle through more; a higher limit removes the flicker. The
+    // sprite overflow flag is unaffected.
+    pub fn set_max_sprites_per_line(&mut self, limit: uint) {
+        self.max_sprites_per_line = limit;
+        self.screen_dirty = true;
+    }
+
+    // Returns the VRAM address that the next PPUDATA access will use.
+    pub fn vram_address(&self) -> uint16_t {
+        self.regs.addr.val & 0x3fff
+    }
+
+    // Reads a byte of VRAM without going through PPUDATA, so the VRAM address and the read
+    // buffer are left alone. For debuggers.
+    pub fn read_vram(&mut self, addr: uint16_t) -> Result<uint8_t,PpuError> {
+        self.vram.checked_loadb(addr)
+    }
+
+    // Returns how far the VRAM address advances after each PPUDATA access: 1 or 32.
+    pub fn vram_increment(&self) -> uint16_t {
+        self.regs.ctrl.vram_addr_increment()
+    }
+
+    // Renders the 256 tiles of pattern table 0 or 1 as a 128x128 RGB image, 16 tiles to a row,
+    // colored with the given background palette row (0-3).
+    pub fn render_pattern_table(&mut self, table: uint8_t, palette_row: uint8_t) -> Vec<uint8_t> {
+        assert!(table < 2, "pattern table must be 0 or 1");
+        assert!(palette_row < 4, "palette row must be between 0 and 3");
+
+        let mut image = Vec::from_elem(128 * 128 * 3, 0u8);
+        for tile in range(0, 256u16) {
+            let tile_addr = (table as uint16_t) * 0x1000 + (tile << 4);
+            for y in range(0, 8u16) {
+                let plane0 = self.vram.chr_loadb_untracked(tile_addr + y);
+                let plane1 = self.vram.chr_loadb_untracked(tile_addr + y + 8);
+                for x in range(0, 8u) {
+                    let bit0 = (plane0 >> (7 - x)) & 1;
+                    let bit1 = (plane1 >> (7 - x)) & 1;
+                    let tile_color = (palette_row << 2) | (bit1 << 1) | bit0;
+                    let palette_index = self.load_palette(tile_color);
+                    let color = self.get_color(palette_index);
+
+                    let px = (tile as uint % 16) * 8 + x;
+                    let py = (tile as uint / 16) * 8 + y as uint;
+                    let offset = (py * 128 + px) * 3;
+                    // `get_color` produces BGR, in screen order.
+                    image[offset + 0] = color.b;
+                    image[offset + 1] = color.g;
+                    image[offset + 2] = color.r;
+                }
+            }
+        }
+        image
+    }
+
+    // Fills `out` with the screen, with each pixel blended half and half with color 1 of the
+    // background palette row its attribute selects, so that it's easy to see which palette each
+    // region of the screen uses. `out` has the same layout as `screen`.
+    pub fn render_attribute_overlay(&mut self, out: &mut [uint8_t]) {
+        assert!(out.len() == SCREEN_WIDTH * SCREEN_HEIGHT * 3, "wrong overlay buffer size");
+
+        for y in range(0, SCREEN_HEIGHT) {
+            for x in range(0, SCREEN_WIDTH) {
+                let row = self.fetch_background_pixel(x as uint8_t, y as uint16_t).palette;
+                let palette_index = self.load_palette(row * 4 + 1);
+                let tint = self.get_color(palette_index);
+
+                let offset = (y * SCREEN_WIDTH + x) * 3;
+                for (i, &channel) in [tint.r, tint.g, tint.b].iter().enumerate() {
+                    let pixel = self.screen[offset + i];
+                    out[offset + i] = ((pixel as uint + channel as uint) / 2) as uint8_t;
+                }
+            }
+        }
+    }
+
+    // Downsamples the screen to a grid of `width` by `height` cells and draws each one as a space
+    // with a 24-bit ANSI background color, for looking at frames in a terminal or a CI log. Each
+    // cell takes the color of the pixel at its center.
+    pub fn to_ansi_string(&self, width: uint, height: uint) -> String {
+        assert!(width > 0 && width <= SCREEN_WIDTH, "width must be between 1 and 256");
+        assert!(height > 0 && height <= SCREEN_HEIGHT, "height must be between 1 and 240");
+
+        let mut result = String::new();
+        for row in range(0, height) {
+            let y = (row * 2 + 1) * SCREEN_HEIGHT / (height * 2);
+            for column in range(0, width) {
+                let x = (column * 2 + 1) * SCREEN_WIDTH / (width * 2);
+
+                // The screen is stored in BGR order.
+                let offset = (y * SCREEN_WIDTH + x) * 3;
+                result.push_str(format!("\x1b[48;2;{};{};{}m ",
+                                        self.screen[offset + 2],
+                                        self.screen[offset + 1],
+                                        self.screen[offset + 0]).as_slice());
+            }
+            result.push_str("\x1b[0m\n");
+        }
+        result
+    }
+
+    // Reduces the screen to 2 bits per pixel for displays that can only show 4 colors. Each pixel
+    // becomes whichever of the 4 colors of background palette row 0 is closest to it in RGB, and
+    // pixels are packed 4 to a byte, leftmost in the top bits.
+    pub fn render_2bpp(&mut self) -> Vec<uint8_t> {
+        let mut targets = [ (0i32, 0i32, 0i32), ..4 ];
+        for i in range(0, 4) {
+            let palette_index = self.load_palette(i as uint8_t);
+            let color = self.get_color(palette_index);
+            targets[i] = (color.r as i32, color.g as i32, color.b as i32);
+        }
+
+        // Work out the nearest target for each of the 64 colors once, rather than per pixel.
+        let mut nearest = [ 0u8, ..64 ];
+        for palette_index in range(0, 64) {
+            let color = self.get_color(palette_index as uint8_t);
+            let (r, g, b) = (color.r as i32, color.g as i32, color.b as i32);
+            let mut best_distance = i32::MAX;
+            for (i, &(tr, tg, tb)) in targets.iter().enumerate() {
+                let distance = (r - tr) * (r - tr) + (g - tg) * (g - tg) + (b - tb) * (b - tb);
+                if distance < best_distance {
+                    best_distance = distance;
+                    nearest[palette_index] = i as uint8_t;
+                }
+            }
+        }
+
+        let mut result = Vec::from_elem(SCREEN_WIDTH * SCREEN_HEIGHT / 4, 0u8);
+        for (i, &palette_index) in self.indexed_screen.iter().enumerate() {
+            let value = nearest[palette_index as uint & 0x3f];
+            result[i / 4] |= value << (6 - (i % 4) * 2);
+        }
+        result
+    }
+
+    // Scales the screen down to `width` by `height` RGB pixels, for save state previews. Each
+    // pixel is the average of the block of screen pixels it covers.
+    pub fn thumbnail(&self, width: uint, height: uint) -> Vec<uint8_t> {
+        assert!(width > 0 && width <= SCREEN_WIDTH, "width must be between 1 and 256");
+        assert!(height > 0 && height <= SCREEN_HEIGHT, "height must be between 1 and 240");
+
+        let mut result = Vec::with_capacity(width * height * 3);
+        for row in range(0, height) {
+            let (top, bottom) = (row * SCREEN_HEIGHT / height, (row + 1) * SCREEN_HEIGHT / height);
+            for column in range(0, width) {
+                let (left, right) = (column * SCREEN_WIDTH / width,
+                                     (column + 1) * SCREEN_WIDTH / width);
+
+                let mut sums = [ 0u, ..3 ];
+                for y in range(top, bottom) {
+                    for x in range(left, right) {
+                        let offset = (y * SCREEN_WIDTH + x) * 3;
+                        for i in range(0, 3) {
+                            sums[i] += self.screen[offset + i] as uint;
+                        }
+                    }
+                }
+
+                // The screen is stored in BGR order.
+                let count = (bottom - top) * (right - left);
+                result.push((sums[2] / count) as uint8_t);
+                result.push((sums[1] / count) as uint8_t);
+                result.push((sums[0] / count) as uint8_t);
+            }
+        }
+        result
+    }
+
+    // Returns which pixels of the given sprite are opaque, one row of 8 per line of the sprite
+    // (8 or 16 rows, depending on the sprite size), with flipping applied as it would be on
+    // screen.
+    pub fn sprite_pixel_mask(&mut self, oam_index: uint8_t) -> Vec<[bool, ..8]> {
+        assert!(oam_index < 64, "OAM index must be below 64");
+
+        let sprite = self.make_sprite_info(oam_index as uint16_t);
+        let height = match self.regs.ctrl.sprite_size() {
+            SpriteSize::Size8x8 => 8,
+            SpriteSize::Size8x16 => 16,
+        };
+
+        let mut mask = Vec::with_capacity(height);
+        for row in range(0, height) {
+            let (tile, y) = self.sprite_tile_row(&sprite, sprite.y + row as uint16_t);
+            let mut pixels = [ false, ..8 ];
+            for column in range(0, 8) {
+                let x = if sprite.flip_horizontal() { 7 - column } else { column };
+                let pattern_color =
+                    self.get_pattern_pixel(PatternPixelKind::Sprite, tile, x as uint8_t, y);
+                pixels[column] = pattern_color != 0;
+            }
+            mask.push(pixels);
+        }
+        mask
+    }
+
+    // Describes how the pixel at the given screen coordinate is produced under the current
+    // scroll. The sprite limit isn't applied, so sprites dropped on a crowded line still show up.
+    pub fn describe_pixel(&mut self, x: uint8_t, y: uint8_t) -> PixelInfo {
+        let background = self.fetch_background_pixel(x, y as uint16_t);
+
+        let mut sprite = None;
+        for index in range(0, 64) {
+            let info = self.make_sprite_info(index);
+            if self.get_sprite_pattern_color(&info, x, y as uint16_t) != 0 {
+                sprite = Some(index as uint8_t);
+                break;
+            }
+        }
+
+        PixelInfo {
+            nametable_addr: background.tile_addr,
+            tile_index: background.tile,
+            palette: background.palette,
+            pattern_color: background.pattern_color,
+            sprite: sprite,
+        }
+    }
+
+    // Enables or disables OAM decay. OAM is dynamic RAM that is only refreshed while the PPU is
+    // rendering, so on real hardware its contents corrupt if rendering stays disabled for too
+    // long. A few test ROMs check for this. Off by default.
+    pub fn set_oam_decay(&mut self, enabled: bool) {
+        self.oam_decay = enabled;
+        self.oam_refresh_cy = self.cy;
+    }
+
+    // Enables or disables the backdrop quirk: while rendering is disabled and the VRAM address
+    // points into palette RAM, the PPU shows the color at that address instead of the backdrop.
+    // A few demos use this to draw with colors other than the backdrop. Off by default, since
+    // hardly anything needs it.
+    pub fn set_backdrop_hack_enabled(&mut self, enabled: bool) {
+        self.backdrop_hack = enabled;
+        self.screen_dirty = true;
+    }
+
+    // Describes the current rendering configuration in a few lines of text, for pasting into bug
+    // reports.
+    pub fn render_config_summary(&self) -> String {
+        fn on_off(enabled: bool) -> &'static str {
+            if enabled { "on" } else { "off" }
+        }
+
+        let mask = self.regs.mask;
+        let ctrl = self.regs.ctrl;
+
+        let mut emphasis = Vec::new();
+        if mask.emphasize_red() { emphasis.push("red"); }
+        if mask.emphasize_green() { emphasis.push("green"); }
+        if mask.emphasize_blue() { emphasis.push("blue"); }
+        let emphasis = if emphasis.is_empty() {
+            "none".to_string()
+        } else {
+            emphasis.connect(", ")
+        };
+
+        format!("Background: {} (left 8 pixels: {})\n\
+                 Sprites: {} (left 8 pixels: {}), {}\n\
+                 Pattern tables: background ${:04X}, sprites ${:04X}\n\
+                 Scroll: X={}, Y={} from nametable ${:04X}\n\
+                 Mirroring: {}\n\
+                 Grayscale: {}\n\
+                 Emphasis: {}",
+                on_off(mask.show_background()),
+                on_off(mask.show_background_left()),
+                on_off(mask.show_sprites()),
+                on_off(mask.show_sprites_left()),
+                match ctrl.sprite_size() {
+                    SpriteSize::Size8x8 => "8x8",
+                    SpriteSize::Size8x16 => "8x16",
+                },
+                ctrl.background_pattern_table_addr(),
+                ctrl.sprite_pattern_table_addr(),
+                self.scroll_x & 0xff,
+                self.scroll_y & 0xff,
+                0x2000 + (self.scroll_x >> 8) * 0x400 + (self.scroll_y >> 8) * 0x800,
+                self.vram.mirroring(),
+                on_off(mask.grayscale()),
+                emphasis)
+    }
+
+    // Describes the PPU's registers and timing as a JSON object, for bug reports and automated
+    // tooling.
+    pub fn diagnostics_json(&self) -> String {
+        let (ctrl, mask, status) = (self.regs.ctrl, self.regs.mask, &self.regs.status);
+        format!("{{\"ctrl\":{{\"value\":{},\"nametable\":{},\"vram_increment\":{},\
+                 \"sprite_pattern_table\":{},\"background_pattern_table\":{},\
+                 \"sprite_height\":{},\"nmi\":{}}},\
+                 \"mask\":{{\"value\":{},\"grayscale\":{},\"show_background_left\":{},\
+                 \"show_sprites_left\":{},\"show_background\":{},\"show_sprites\":{},\
+                 \"emphasize_red\":{},\"emphasize_green\":{},\"emphasize_blue\":{}}},\
+                 \"status\":{{\"value\":{},\"sprite_overflow\":{},\"sprite_zero_hit\":{},\
+                 \"vblank\":{}}},\
+                 \"oam_addr\":{},\"vram_addr\":{},\
+                 \"scroll\":{{\"x\":{},\"y\":{},\"nametable\":{}}},\
+                 \"mirroring\":\"{}\",\"frame\":{},\"scanline\":{},\"dot\":{},\"cycle\":{}}}",
+                *ctrl,
+                0x2000 + (*ctrl as uint & 3) * 0x400,
+                ctrl.vram_addr_increment(),
+                ctrl.sprite_pattern_table_addr(),
+                ctrl.background_pattern_table_addr(),
+                match ctrl.sprite_size() {
+                    SpriteSize::Size8x8 => 8u,
+                    SpriteSize::Size8x16 => 16,
+                },
+                ctrl.vblank_nmi(),
+                *mask,
+                mask.grayscale(),
+                mask.show_background_left(),
+                mask.show_sprites_left(),
+                mask.show_background(),
+                mask.show_sprites(),
+                mask.emphasize_red(),
+                mask.emphasize_green(),
+                mask.emphasize_blue(),
+                **status,
+                status.sprite_overflow(),
+                status.sprite_zero_hit(),
+                status.in_vblank(),
+                self.regs.oam_addr,
+                self.regs.addr.val & 0x3fff,
+                self.scroll_x & 0xff,
+                self.scroll_y & 0xff,
+                0x2000 + (self.scroll_x >> 8) * 0x400 + (self.scroll_y >> 8) * 0x800,
+                self.vram.mirroring(),
+                self.frame_count,
+                self.scanline,
+                self.dot,
+                self.cy)
+    }
+
+    // Forces palette RAM entry `index` (0-31) to display as the given 6-bit palette index, or
+    // gives it back to the game if `None`. The game can still write the entry, and reads it back
+    // as usual, but rendering uses the override. Mirrored entries share overrides.
+    pub fn set_palette_override(&mut self, index: uint8_t, color: Option<uint8_t>) {
+        assert!(index < 0x20, "palette index must be below 0x20");
+        self.palette_overrides[Vram::palette_index(index as uint16_t)] = color.map(|c| c & 0x3f);
+        self.vram.palette_changed = true;
+        self.screen_dirty = true;
+    }
+
+    // Makes every pixel drawn from now on show `remap[i]` where it would have shown color `i`,
+    // for example to swap colors that are hard to tell apart for color-blind players. Only the
+    // display changes; palette RAM reads as before. `None` or the identity mapping turns it off.
+    pub fn set_display_palette_remap(&mut self, remap: Option<[uint8_t, ..64]>) {
+        self.display_remap = match remap {
+            Some(remap) if range(0, 64).any(|i| remap[i] != i as uint8_t) => {
+                let mut masked = [ 0, ..64 ];
+                for i in range(0, 64) {
+                    masked[i] = remap[i] & 0x3f;
+                }
+                Some(masked)
+            }
+            _ => None,
+        };
+        self.color_cache_mask = None;
+        self.screen_dirty = true;
+    }
+
+    // Returns the RGB color this PPU produces for each of the 64 palette indices.
+    pub fn rgb_palette(&self) -> [uint8_t, ..192] {
+        let mut result = [ 0, ..192 ];
+        for i in range(0, 64) {
+            // `get_color` produces BGR.
+            let color = self.get_color(i as uint8_t);
+            result[i * 3 + 0] = color.b;
+            result[i * 3 + 1] = color.g;
+            result[i * 3 + 2] = color.r;
+        }
+        result
+    }
+
+    // Returns the contents of palette RAM as seen through PPUDATA, with the mirrored entries
+    // filled in.
+    pub fn palette_ram(&self) -> [uint8_t, ..0x20] {
+        let mut result = [ 0, ..0x20 ];
+        for i in range(0, 0x20) {
+            result[i] = self.vram.palette[Vram::palette_index(i as uint16_t)];
+        }
+        result
+    }
+
+    // Returns one channel of the screen (0 = red, 1 = green, 2 = blue) as a 256x240 plane.
+    pub fn channel_plane(&self, channel: uint8_t) -> Vec<uint8_t> {
+        assert!(channel < 3, "channel must be 0 (red), 1 (green), or 2 (blue)");
+
+        // The screen is stored in BGR order.
+        let offset = 2 - channel as uint;
+        let mut plane = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT);
+        for i in range(0, SCREEN_WIDTH * SCREEN_HEIGHT) {
+            plane.push(self.screen[i * 3 + offset]);
+        }
+        plane
+    }
+
+    //
+    // Color utilities
+    //
+
+    // Looks up the color of a palette index, ignoring PPUMASK.
+    #[inline(always)]
+    fn get_color(&self, palette_index: uint8_t) -> Rgb {
+        self.get_masked_color(palette_index, PpuMask { val: 0 })
+    }
+
+    // Looks up the color of a palette index as displayed under the given PPUMASK.
+    #[inline(always)]
+    fn get_masked_color(&self, palette_index: uint8_t, mask: PpuMask) -> Rgb {
+        let palette = match self.custom_palette {
+            Some(ref palette) => &**palette,
+            None => self.variant.palette(self.tv_system),
+        };
+        resolve_color(palette_index,
+                      mask,
+                      self.display_remap.as_ref(),
+                      self.variant.palette_permutation(),
+                      palette)
+    }
+
+    // Like `get_masked_color`, but looks the color up in a table of all 64 colors as displayed
+    // under the grayscale and emphasis bits of `mask`. The table is rebuilt when those bits change
+    // or when the TV system, variant, custom palette, or display remap does. It's indexed by
+    // color rather than by palette RAM entry, so palette writes and overrides don't affect it.
+    #[inline(always)]
+    fn cached_color(&mut self, palette_index: uint8_t, mask: PpuMask) -> Rgb {
+        let color_bits = *mask & 0xe1;
+        if self.color_cache_mask != Some(color_bits) {
+            let mask = PpuMask { val: color_bits };
+            for i in range(0, 64) {
+                self.color_cache[i] = self.get_masked_color(i as uint8_t, mask);
+            }
+            self.color_cache_mask = Some(color_bits);
+        }
+        self.color_cache[palette_index as uint & 0x3f]
+    }
+
+    //
+    // Register manipulation
+    //
+
+    fn update_ppuctrl(&mut self, val: uint8_t) {
+        let nmi_was_enabled = self.regs.ctrl.vblank_nmi();
+        self.regs.ctrl = PpuCtrl{val:val};
+
+        // Enabling NMI while the vblank flag is set raises an NMI straight away. A write that
+        // lands on the dot where the flag is cleared (dot 1 of the pre-render scanline) is too
+        // late, though, even though our flag is only cleared when that dot is ticked.
+        let vblank_ending = self.scanline == (LAST_SCANLINE as uint16_t) && self.dot == 1;
+        if !nmi_was_enabled && self.regs.ctrl.vblank_nmi() && self.regs.status.in_vblank() &&
+                !vblank_ending {
+            self.nmi_pending = true;
+        }
+
+        self.next_nametable_x = self.regs.ctrl.x_scroll_offset();
+        self.next_nametable_y = self.regs.ctrl.y_scroll_offset();
+    }
+
+    fn update_ppuscroll(&mut self, val: uint8_t) {
+        match self.regs.write_toggle {
+            WriteToggle::First => {
+                self.scroll_x = (self.scroll_x & 0xff00) | (val as uint16_t);
+
+                self.regs.scroll.x = val;
+                self.regs.write_toggle = WriteToggle::Second;
+            }
+            WriteToggle::Second => {
+                self.scroll_y = (self.scroll_y & 0xff00) | (val as uint16_t);
+
+                self.regs.scroll.y = val;
+                self.regs.write_toggle = WriteToggle::First;
+            }
+        }
+    }
+
+    fn write_oamdata(&mut self, val: uint8_t) {
+        // While the PPU is rendering, it owns OAM. A write then stores nothing and instead bumps
+        // the sprite index in the top 6 bits of OAMADDR.
+        if self.rendering_active() {
+            self.regs.oam_addr += 4;
+            return;
+        }
+
+        self.oam.storeb(self.regs.oam_addr as uint16_t, val);
+        self.regs.oam_addr += 1;
+    }
+
+    fn read_oamdata(&mut self) -> uint8_t {
+        let val = self.oam.loadb(self.regs.oam_addr as uint16_t);
+
+        // Bits 2-4 of the attribute byte don't exist and read back as zero.
+        if (self.regs.oam_addr & 3) == 2 { val & 0xe3 } else { val }
+    }
+
+    fn update_ppuaddr(&mut self, val: uint8_t) {
+        match self.regs.write_toggle {
+            WriteToggle::First => {
+                // The VRAM address is only 14 bits wide, so the top two bits are dropped.
+                let hi = (val & 0x3f) as uint16_t;
+                self.regs.addr.val = (self.regs.addr.val & 0x00ff) | (hi << 8);
+                self.regs.write_toggle = WriteToggle::Second;
+            }
+            WriteToggle::Second => {
+                self.regs.addr.val = (self.regs.addr.val & 0xff00) | (val as uint16_t);
+                self.regs.write_toggle = WriteToggle::First;
+
+                // Adjust the scroll registers.
+                // TODO: This is pretty much a hack. The right way is to precisely emulate the PPU
+                // internal registers.
+                // TODO: Y scrolling.
+                let addr = self.regs.addr.val & 0x07ff;
+                let xscroll_base = if addr < 0x400 { 0 } else { 256 };
+                self.scroll_x = (self.scroll_x & 0xff) | xscroll_base;
+
+                // The first write also replaced the PPUCTRL nametable bits.
+                self.next_nametable_x = (self.regs.addr.val & 0x0400) >> 2;
+                self.next_nametable_y = (self.regs.addr.val & 0x0800) >> 3;
+            }
+        }
+    }
+
+    fn read_ppustatus(&mut self) -> uint8_t {
+        // Reset latch.
+        self.regs.write_toggle = WriteToggle::First;
+
+        // Only the top 3 bits are driven; the rest come from the data bus, except on the 2C05.
+        let low_bits = match self.variant.status_id() {
+            None => self.io_latch & 0x1f,
+            Some(id) => id,
+        };
+        let status = (*self.regs.status & 0xe0) | low_bits;
+
+        // Reading the status clears the vblank flag, so it reads as set at most once per frame.
+        self.regs.status.set_in_vblank(false);
+        status
+    }
+
+    // The VRAM address is 14 bits wide, so incrementing past $3FFF wraps around to $0000.
+    //
+    // While the PPU is rendering, the VRAM address is in use as the scroll position, and a
+    // PPUDATA access bumps it the way rendering does at the end of a tile and of a scanline
+    // instead: coarse X and Y are both incremented, carrying into the nametable bits. Games
+    // that do this end up with a corrupted address (and, on hardware, a glitched picture).
+    //
+    // Only the address is affected. The renderer draws from `scroll_x` and `scroll_y`, not from
+    // the VRAM address, so the jump in the picture that hardware shows is not emulated.
+    fn increment_vram_addr(&mut self) {
+        if self.rendering_active() {
+            self.regs.addr.val = Ppu::glitch_vram_addr(self.regs.addr.val);
+            return;
+        }
+
+        let increment = self.regs.ctrl.vram_addr_increment();
+        self.regs.addr.val = (self.regs.addr.val + increment) & 0x3fff;
+    }
+
+    // Applies the coarse X and Y increments that rendering makes to the VRAM address. The layout
+    // is 0yyy NNYY YYYX XXXX: fine Y, nametable, coarse Y, and coarse X. The top bit of fine Y is
+    // outside the 14-bit address space, so accesses mask it off, but it is kept here.
+    fn glitch_vram_addr(addr: uint16_t) -> uint16_t {
+        let mut addr = addr;
+
+        // Coarse X, wrapping into the horizontal nametable bit.
+        if (addr & 0x001f) == 31 {
+            addr = (addr & !0x001f) ^ 0x0400;
+        } else {
+            addr += 1;
+        }
+
+        // Y, going through fine Y first, then coarse Y, which wraps at 30 into the vertical
+        // nametable bit.
+        if (addr & 0x7000) != 0x7000 {
+            addr += 0x1000;
+        } else {
+            addr &= !0x7000;
+            let coarse_y = (addr & 0x03e0) >> 5;
+            addr = match coarse_y {
+                29 => (addr & !0x03e0) ^ 0x0800,
+                31 => addr & !0x03e0,
+                _ => (addr & !0x03e0) | ((coarse_y + 1) << 5),
+            };
+        }
+
+        addr & 0x7fff
+    }
+
+    fn write_ppudata(&mut self, val: uint8_t) {
+        self.vram.storeb(self.regs.addr.val & 0x3fff, val);
+        self.increment_vram_addr();
+    }
+
+    fn read_ppudata(&mut self) -> uint8_t {
+        let addr = self.regs.addr.val & 0x3fff;
+        let val = self.vram.loadb(addr);
+        self.increment_vram_addr();
+
+        // Emulate the PPU buffering quirk.
+        if addr < 0x3f00 {
+            let buffered_val = self.ppudata_buffer;
+            self.ppudata_buffer = val;
+            buffered_val
+        } else {
+            val
+        }
+    }
+
+    //
     // Background rendering helpers
     //
 
@@ -610,10 +2170,34 @@ impl Ppu {
         }
     }
 
+    // Maps a screen row to the vertical nametable (0 or 1) and the pixel row within it that the
+    // background is fetched from.
+    //
+    // Nametables are 240 lines tall, so scrolling normally wraps into the other nametable after
+    // row 239. But PPUSCROLL accepts Y values of 240-255 too. The PPU then starts fetching from
+    // tile rows 30 and 31, which lie in the attribute table, so attribute bytes show up as
+    // garbage tiles; and when the coarse Y counter wraps from 31 to 0 it stays in the same
+    // nametable.
+    fn background_row(&self, y: uint16_t) -> (uint16_t, uint16_t) {
+        let nametable_y = self.scroll_y >> 8;
+        let fine_y = self.scroll_y & 0xff;
+        let row = fine_y + y;
+        if fine_y < 240 {
+            if row < 240 { (nametable_y, row) } else { (nametable_y ^ 1, row - 240) }
+        } else {
+            if row < 256 { (nametable_y, row) } else { (nametable_y, row - 256) }
+        }
+    }
+
+    // OAM stores each sprite's Y coordinate minus one, so a sprite is first drawn on the
+    // scanline after its OAM Y value: Y=0 starts on scanline 1, and Y=238 on scanline 239, where
+    // only its top row is visible. Y=239 through Y=255 start on scanlines 240 through 256, below
+    // the picture, which is how games hide sprites. The addition is done in 16 bits so that
+    // Y=255 doesn't wrap around to the top of the screen.
     #[inline(always)]
     fn make_sprite_info(&mut self, index: uint16_t) -> SpriteStruct {
         SpriteStruct {
-            y: self.oam.loadb(index * 4 + 0) + 1,
+            y: self.oam.loadb(index * 4 + 0) as uint16_t + 1,
             tile_index_byte: self.oam.loadb(index * 4 + 1),
             attribute_byte: self.oam.loadb(index * 4 + 2),
             x: self.oam.loadb(index * 4 + 3),
@@ -630,15 +2214,44 @@ impl Ppu {
         }
     }
 
-    //
-    // Rendering
-    //
-
-    #[inline(always)]
-    fn putpixel(&mut self, x: uint, y: uint, color: Rgb) {
-        self.screen[(y * SCREEN_WIDTH + x) * 3 + 0] = color.r;
-        self.screen[(y * SCREEN_WIDTH + x) * 3 + 1] = color.g;
-        self.screen[(y * SCREEN_WIDTH + x) * 3 + 2] = color.b;
+    //
+    // Rendering
+    //
+
+    #[inline(always)]
+    fn putpixel(&mut self, x: uint, y: uint, color: Rgb) {
+        let offset = (y * SCREEN_WIDTH + x) * 3;
+        if self.screen[offset + 0] != color.r ||
+                self.screen[offset + 1] != color.g ||
+                self.screen[offset + 2] != color.b {
+            self.dirty_scanlines[y] = true;
+        }
+
+        self.screen[offset + 0] = color.r;
+        self.screen[offset + 1] = color.g;
+        self.screen[offset + 2] = color.b;
+        self.render_stats.pixels_drawn += 1;
+    }
+
+    // Looks up palette RAM entry `index` for rendering, taking overrides into account. Every
+    // pixel needs one of these, so the resolved entries are cached until the palette changes.
+    #[inline(always)]
+    fn load_palette(&mut self, index: uint8_t) -> uint8_t {
+        if self.vram.palette_changed {
+            self.refresh_palette_cache();
+        }
+        self.palette_cache[index as uint & 0x1f]
+    }
+
+    fn refresh_palette_cache(&mut self) {
+        for i in range(0, 0x20) {
+            let entry = Vram::palette_index(i as uint16_t);
+            self.palette_cache[i] = match self.palette_overrides[entry] {
+                Some(color) => color,
+                None => self.vram.palette[entry],
+            };
+        }
+        self.vram.palette_changed = false;
     }
 
     // Returns the color (pre-palette lookup) of pixel (x,y) within the given tile.
@@ -649,8 +2262,8 @@ impl Ppu {
         match kind {
             PatternPixelKind::Background =>
                 pattern_offset += self.regs.ctrl.background_pattern_table_addr(),
-            PatternPixelKind::Sprite     =>
-                pattern_offset += self.regs.ctrl.sprite_pattern_table_addr(),
+            // Sprite tile indices already include the pattern table; see `SpriteTiles`.
+            PatternPixelKind::Sprite     => {}
         }
 
         // Determine the color of this pixel.
@@ -661,30 +2274,32 @@ impl Ppu {
         (bit1 << 1) | bit0
     }
 
-    // Returns true if the background was opaque here, false otherwise.
-    #[inline(always)]
-    fn get_background_pixel(&mut self, x: uint8_t) -> Option<Rgb> {
+    // Fetches the background tile, attribute palette, and pattern color at the given screen
+    // coordinate under the current scroll. Both the attribute and pattern fetches are done even
+    // when the pixel turns out to be transparent.
+    fn fetch_background_pixel(&mut self, x: uint8_t, y: uint16_t) -> BackgroundPixel {
         // Adjust X and Y to account for scrolling.
         let x = x as uint16_t + self.scroll_x;
-        let y = self.scanline as uint16_t + self.scroll_y;
+        let (nametable_y, row) = self.background_row(y);
 
         // Compute the nametable address, tile index, and pixel offset within that tile.
-        let NametableAddr { base, x_index, y_index } = self.nametable_addr(x / 8, y / 8);
-        let (xsub, ysub) = ((x % 8) as uint8_t, (y % 8) as uint8_t);
+        let NametableAddr { base, x_index, .. } = self.nametable_addr(x / 8, nametable_y * 30);
+        let y_index = (row / 8) as uint8_t;
+        let (xsub, ysub) = ((x % 8) as uint8_t, (row % 8) as uint8_t);
 
         // Compute the nametable address and load the tile number from the nametable.
-        let tile = self.vram.loadb(base + 32 * (y_index as uint16_t) + (x_index as uint16_t));
+        let tile_addr = base + 32 * (y_index as uint16_t) + (x_index as uint16_t);
+        let tile = self.vram.loadb(tile_addr);
 
         // Fetch the pattern color.
         let pattern_color = self.get_pattern_pixel(PatternPixelKind::Background,
                                                    tile as uint16_t,
                                                    xsub,
                                                    ysub);
-        if pattern_color == 0 {
-            return None;    // Transparent.
-        }
 
-        // Now load the attribute bits from the attribute table.
+        // Now load the attribute bits from the attribute table. This uses the nametable base
+        // computed for this pixel's tile, so pixels on either side of a nametable seam take their
+        // attributes from their own nametable.
         let group = y_index / 4 * 8 + x_index / 4;
         let attr_byte = self.vram.loadb(base + 0x3c0 + (group as uint16_t));
         let (left, top) = (x_index % 4 < 2, y_index % 4 < 2);
@@ -695,170 +2310,964 @@ impl Ppu {
             (false, false) => (attr_byte >> 6) & 0x3
         };
 
+        BackgroundPixel {
+            tile_addr: tile_addr,
+            tile: tile,
+            palette: attr_table_color,
+            pattern_color: pattern_color,
+        }
+    }
+
+    // Returns the palette index of the background at the given X coordinate on the current
+    // scanline, or None if the background is transparent there.
+    #[inline(always)]
+    fn get_background_pixel(&mut self, x: uint8_t) -> Option<uint8_t> {
+        let scanline = self.scanline;
+        let pixel = self.fetch_background_pixel(x, scanline);
+        if pixel.pattern_color == 0 {
+            return None;    // Transparent.
+        }
+
         // Determine the final color and fetch the palette from VRAM.
-        let tile_color = (attr_table_color << 2) | pattern_color;
-        let palette_index = self.vram.loadb(0x3f00 + (tile_color as uint16_t)) & 0x3f;
-        return Some(self.get_color(palette_index));
-    }
-
-    fn get_sprite_pixel(&mut self,
-                        visible_sprites: &[Option<uint8_t>, ..8],
-                        x: uint8_t,
-                        background_opaque: bool)
-                     -> Option<SpriteColor> {
-        for &visible_sprite_opt in visible_sprites.iter() {
-            match visible_sprite_opt {
-                None => return None,
-                Some(index) => {
-                    let sprite = self.make_sprite_info(index as uint16_t);
+        let tile_color = (pixel.palette << 2) | pixel.pattern_color;
+        return Some(self.load_palette(tile_color));
+    }
 
-                    // Don't need to consider this sprite if we aren't in its bounding box.
-                    if !sprite.in_bounding_box(self, x as uint8_t, self.scanline as uint8_t) {
-                        continue
-                    }
+    // Returns the pattern color of the given sprite at the given screen coordinate, or 0 if the
+    // sprite is transparent there or doesn't cover it.
+    fn get_sprite_pattern_color(&mut self, sprite: &SpriteStruct, x: uint8_t, y: uint16_t)
+                                -> uint8_t {
+        // Don't need to consider this sprite if we aren't in its bounding box.
+        if !sprite.in_bounding_box(self, x, y) {
+            return 0;
+        }
 
-                    let pattern_color;
-                    match sprite.tiles(self) {
-                        // TODO: 8x16 rendering
-                        SpriteTiles::Tiles8x8(tile) | SpriteTiles::Tiles8x16(tile, _) => {
-                            let mut x = x - sprite.x;
-                            if sprite.flip_horizontal() { x = 7 - x; }
+        // This can't underflow, since the point is in the sprite's bounding box.
+        let mut x = x - sprite.x;
+        if sprite.flip_horizontal() { x = 7 - x; }
 
-                            let mut y = self.scanline as uint8_t - sprite.y;
-                            if sprite.flip_vertical() { y = 7 - y; }
+        let (tile, y) = self.sprite_tile_row(sprite, y);
 
-                            debug_assert(x < 8, "sprite X miscalculation");
-                            debug_assert(y < 8, "sprite Y miscalculation");
+        debug_assert(x < 8, "sprite X miscalculation");
+        debug_assert(y < 8, "sprite Y miscalculation");
 
-                            pattern_color = self.get_pattern_pixel(PatternPixelKind::Sprite,
-                                                                   tile,
-                                                                   x,
-                                                                   y);
-                        }
-                    }
+        self.get_pattern_pixel(PatternPixelKind::Sprite, tile, x, y)
+    }
 
-                    // If the pattern color was zero, this part of the sprite is transparent.
-                    if pattern_color == 0 {
-                        continue
-                    }
+    // Returns the tile, and the row within that tile, that the given sprite draws on scanline
+    // `y`, taking vertical flipping into account. The sprite must be on that scanline.
+    fn sprite_tile_row(&self, sprite: &SpriteStruct, y: uint16_t) -> (uint16_t, uint8_t) {
+        let mut y = (y - sprite.y) as uint8_t;
+        let tile = match sprite.tiles(self) {
+            SpriteTiles::Tiles8x8(tile) => {
+                if sprite.flip_vertical() { y = 7 - y; }
+                tile
+            }
+            SpriteTiles::Tiles8x16(top, bottom) => {
+                // Vertical flip mirrors the sprite as a whole, so the bottom tile is drawn
+                // flipped at the top and vice versa.
+                if sprite.flip_vertical() { y = 15 - y; }
+                if y < 8 {
+                    top
+                } else {
+                    y -= 8;
+                    bottom
+                }
+            }
+        };
+        (tile, y)
+    }
 
-                    // OK, so we know this pixel is opaque. Now if this is the first sprite and the
-                    // background was not transparent, set sprite 0 hit.
-                    if index == 0 && background_opaque {
-                        self.regs.status.set_sprite_zero_hit(true);
-                    }
+    fn get_sprite_pixel(&mut self, x: uint8_t, background_opaque: bool) -> Option<SpriteColor> {
+        let pixel = match self.sprite_pixels[x as uint] {
+            None => return None,
+            Some(pixel) => pixel,
+        };
+
+        // If this is the first sprite and the background was not transparent, set sprite 0 hit.
+        // In accurate mode this runs on the dot that draws the pixel, so the CPU sees the flag
+        // appear at the right time; in fast mode it appears when the scanline starts.
+        if pixel.index == 0 && background_opaque && !self.regs.status.sprite_zero_hit() {
+            self.regs.status.set_sprite_zero_hit(true);
+            let scanline = self.scanline;
+            self.log_event(PpuEvent::SpriteZeroHit { scanline: scanline, x: x });
+        }
+
+        // Do the palette lookup.
+        let palette_index = self.load_palette(pixel.tile_color);
+
+        return Some(SpriteColor {
+            priority: pixel.priority,
+            palette_index: palette_index,
+        });
+    }
+
+    // Works out which of the visible sprites is frontmost and opaque at each X on the current
+    // scanline, so that rendering a pixel only costs a lookup. The hardware likewise fetches all
+    // sprite patterns for a line ahead of time, at the end of the previous line.
+    fn latch_sprite_pixels(&mut self) {
+        for pixel in self.sprite_pixels.iter_mut() {
+            *pixel = None;
+        }
 
-                    // Determine final tile color and do the palette lookup.
-                    let tile_color = (sprite.palette() << 2) | pattern_color;
-                    let palette_index = self.vram.loadb(0x3f00 + (tile_color as uint16_t)) & 0x3f;
-                    let final_color = self.get_color(palette_index);
+        let scanline = self.scanline;
+        for i in range(0, self.visible_sprites.len()) {
+            let index = self.visible_sprites[i];
+            let sprite = self.make_sprite_info(index as uint16_t);
+            for x in range(sprite.x as uint, cmp::min(sprite.x as uint + 8, SCREEN_WIDTH)) {
+                // Sprites earlier in OAM are in front.
+                if self.sprite_pixels[x].is_some() {
+                    continue
+                }
 
-                    return Some(SpriteColor { priority: sprite.priority(), color: final_color });
+                let pattern_color = self.get_sprite_pattern_color(&sprite, x as uint8_t, scanline);
+                if pattern_color == 0 {
+                    continue    // Transparent.
                 }
+
+                self.sprite_pixels[x] = Some(SpritePixel {
+                    index: index,
+                    priority: sprite.priority(),
+                    tile_color: (sprite.palette() << 2) | pattern_color,
+                });
             }
         }
-        return None;
     }
 
-    fn compute_visible_sprites(&mut self) -> [Option<uint8_t>, ..8] {
+    // Selects the first eight sprites (in OAM order) on the current scanline into
+    // `visible_sprites` and evaluates the sprite overflow flag. If the sprite limit has been
+    // raised above eight, further sprites are selected too, but the overflow flag still follows
+    // the hardware's eight-sprite limit so that games behave the same.
+    //
+    // Overflow evaluation emulates the hardware bug. Once eight sprites have been found, the PPU
+    // keeps scanning OAM for a ninth, but every time a sprite turns out *not* to be on the
+    // scanline it increments the byte offset within the sprite (m) along with the sprite index
+    // (n), wrapping m from 3 back to 0 without carrying into n. So the PPU compares OAM[n*4+0],
+    // then OAM[(n+1)*4+1], OAM[(n+2)*4+2], OAM[(n+3)*4+3], OAM[(n+4)*4+0], ... against the
+    // scanline, treating tile indices, attributes, and X coordinates as Y coordinates. The flag
+    // is set as soon as one of these bytes is in range. This both misses real ninth sprites
+    // (false negatives) and flags lines that have only eight (false positives).
+    fn compute_visible_sprites(&mut self) {
+        let scanline = self.scanline;
+        let limit = self.max_sprites_per_line;
         let mut count = 0;
-        let mut result = [None, ..8];
+        let mut next_index = 64;
+        self.visible_sprites.clear();
         self.each_sprite(|this, sprite, index| {
-            if sprite.on_scanline(this, this.scanline as uint8_t) {
-                if count < 8 {
-                    result[count] = Some(index);
-                    count += 1;
-                    true
-                } else {
-                    this.regs.status.set_sprite_overflow(true);
-                    false
+            if sprite.on_scanline(this, scanline) {
+                if count < limit {
+                    this.visible_sprites.push(index);
+                }
+                count += 1;
+                if count == 8 {
+                    next_index = index as uint16_t + 1;
                 }
-            } else {
-                true
             }
+            // Keep counting past the limit, for the render stats.
+            true
         });
-        result
+        self.latch_sprite_pixels();
+        self.render_stats.max_sprites_per_line =
+            cmp::max(self.render_stats.max_sprites_per_line, count as uint8_t);
+
+        let (mut n, mut m) = (next_index, 0);
+        while n < 64 {
+            // Only the Y coordinate matters for the range check.
+            let candidate = SpriteStruct {
+                y: self.oam.loadb(n * 4 + m) as uint16_t + 1,
+                tile_index_byte: 0,
+                attribute_byte: 0,
+                x: 0,
+            };
+            if candidate.on_scanline(self, scanline) {
+                self.regs.status.set_sprite_overflow(true);
+                let scanline = self.scanline;
+                self.log_event(PpuEvent::SpriteOverflow(scanline));
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 3;
+        }
     }
 
+    // Draws the whole of the current scanline at once. In fast mode this happens at the start of
+    // the scanline, so every pixel on it uses the scroll registers as they were at that point, and
+    // scroll writes made during the scanline (such as a status bar split) take effect on the next.
     fn render_scanline(&mut self) {
-        // TODO: Scrolling, mirroring
-        let visible_sprites = self.compute_visible_sprites();
+        self.compute_visible_sprites();
+        for x in range(0, SCREEN_WIDTH) {
+            // FIXME: For performance, we shouldn't be recomputing the tile for every pixel.
+            self.render_pixel(x);
+        }
+    }
+
+    // Whether the current scanline gets drawn under the render budget.
+    fn within_render_budget(&self) -> bool {
+        match self.render_budget {
+            None => true,
+            Some(budget) => self.render_stats.scanlines_rendered < budget,
+        }
+    }
 
-        let backdrop_color_index = self.vram.loadb(0x3f00) & 0x3f;
-        let backdrop_color = self.get_color(backdrop_color_index);
+    // Whether the current scanline gets drawn at all: it must be within the render budget, and
+    // with the frame cache on, something must have changed since the last frame drawn.
+    fn should_draw_scanline(&self) -> bool {
+        let changed = !self.frame_cache || self.frame_dirty || self.screen_dirty;
+        changed && self.within_render_budget()
+    }
 
+    // Does the part of rendering the current scanline that games can observe, setting the
+    // sprite overflow and sprite 0 hit flags, without drawing anything.
+    fn skip_scanline(&mut self) {
+        self.compute_visible_sprites();
         for x in range(0, SCREEN_WIDTH) {
-            // FIXME: For performance, we shouldn't be recomputing the tile for every pixel.
-            let mut background_color = None;
-            if self.regs.mask.show_background() {
-                background_color = self.get_background_pixel(x as uint8_t);
+            self.skip_pixel(x);
+        }
+    }
+
+    // Like `render_pixel`, but only sets the sprite 0 hit flag.
+    fn skip_pixel(&mut self, x: uint) {
+        let sprite_zero = match self.sprite_pixels[x] {
+            Some(pixel) => pixel.index == 0,
+            None => false,
+        };
+        if !sprite_zero || self.regs.status.sprite_zero_hit() {
+            return;
+        }
+
+        let in_left_column = x < 8;
+        if !self.regs.mask.show_background() ||
+                (in_left_column && !self.regs.mask.show_background_left()) ||
+                !self.regs.mask.show_sprites() ||
+                (in_left_column && !self.regs.mask.show_sprites_left()) {
+            return;
+        }
+
+        if self.get_background_pixel(x as uint8_t).is_some() {
+            self.get_sprite_pixel(x as uint8_t, true);
+        }
+    }
+
+    // Renders the pixel at X on the current scanline. PPUMASK is sampled here for every pixel, so
+    // in accurate mode, disabling rendering partway through a scanline shows the backdrop from
+    // that dot on, and toggling grayscale splits the line.
+    #[inline(always)]
+    fn render_pixel(&mut self, x: uint) {
+        // The border takes the backdrop color from the start of the line.
+        if x == 0 {
+            let backdrop = self.backdrop();
+            let mask = self.regs.mask;
+            let color = self.cached_color(backdrop, mask);
+            let offset = self.scanline as uint * 3;
+            self.border_colors[offset + 0] = color.r;
+            self.border_colors[offset + 1] = color.g;
+            self.border_colors[offset + 2] = color.b;
+        }
+
+        // The leftmost 8 pixels of each layer can be clipped. A clipped background pixel counts
+        // as transparent, so sprite 0 can't hit there either.
+        let in_left_column = x < 8;
+
+        let mut background_color = None;
+        if self.regs.mask.show_background() &&
+                (!in_left_column || self.regs.mask.show_background_left()) {
+            background_color = self.get_background_pixel(x as uint8_t);
+        }
+
+        let mut sprite_color = None;
+        if self.regs.mask.show_sprites() &&
+                (!in_left_column || self.regs.mask.show_sprites_left()) {
+            sprite_color = self.get_sprite_pixel(x as uint8_t, background_color.is_some());
+        }
+
+        // Combine colors using priority.
+        let (palette_index, layer) = match (background_color, sprite_color) {
+            (None, None) => (self.backdrop(), LAYER_BACKDROP),
+            (Some(index), None) => (index, LAYER_BACKGROUND),
+            (Some(index), Some(SpriteColor { priority: SpritePriority::BelowBg, .. })) => {
+                (index, LAYER_BACKGROUND)
+            }
+            (None, Some(SpriteColor { priority: SpritePriority::BelowBg, palette_index: index })) => {
+                (index, LAYER_SPRITE_BELOW_BG)
             }
+            (_, Some(SpriteColor { priority: SpritePriority::AboveBg, palette_index: index })) => {
+                (index, LAYER_SPRITE_ABOVE_BG)
+            }
+        };
+
+        let scanline = self.scanline;
+        let mask = self.regs.mask;
+        let color = self.cached_color(palette_index, mask);
+        self.putpixel(x, scanline as uint, color);
+
+        // The indexed screen records the index as displayed, which grayscale mode reduces to
+        // its luminance bits.
+        let palette_index = if self.regs.mask.grayscale() {
+            palette_index & 0x30
+        } else {
+            palette_index
+        };
+        let offset = scanline as uint * SCREEN_WIDTH + x;
+        self.indexed_screen[offset] = palette_index;
+        self.layers[offset] = layer;
+    }
+
+    // Redraws all visible scanlines from the current VRAM and register state and returns the
+    // screen. This replaces the frame that `step` drew, so mid-frame changes such as scroll
+    // splits and CHR bank switches are lost; to get the emulated frame, read `screen` instead.
+    // Nothing else is affected: the PPU's timing, status flags, sprite evaluation, render stats,
+    // and event log are left as they were.
+    pub fn render_frame(&mut self) -> &[uint8_t] {
+        if self.frame_cache && !self.screen_dirty && !self.frame_dirty {
+            return self.screen.as_slice();
+        }
+
+        let state = self.save_render_state();
+        for y in range(0, SCREEN_HEIGHT) {
+            self.scanline = y as uint16_t;
+            self.render_scanline();
+        }
+        self.restore_render_state(state);
+        self.screen_dirty = false;
+        self.frame_dirty = false;
+
+        self.screen.as_slice()
+    }
+
+    fn save_render_state(&self) -> RenderState {
+        RenderState {
+            scanline: self.scanline,
+            status: *self.regs.status,
+            visible_sprites: self.visible_sprites.clone(),
+            sprite_pixels: box() (*self.sprite_pixels),
+            render_stats: self.render_stats,
+            border_colors: self.border_colors,
+            dirty_scanlines: self.dirty_scanlines,
+            event_count: match self.event_log {
+                Some(ref log) => log.len(),
+                None => 0,
+            },
+        }
+    }
+
+    fn restore_render_state(&mut self, state: RenderState) {
+        self.scanline = state.scanline;
+        self.regs.status = PpuStatus { val: state.status };
+        self.visible_sprites = state.visible_sprites;
+        self.sprite_pixels = state.sprite_pixels;
+        self.render_stats = state.render_stats;
+        self.border_colors = state.border_colors;
+        self.dirty_scanlines = state.dirty_scanlines;
+        match self.event_log {
+            Some(ref mut log) => log.truncate(state.event_count),
+            None => {}
+        }
+    }
 
-            let mut sprite_color = None;
-            if self.regs.mask.show_sprites() {
-                sprite_color = self.get_sprite_pixel(&visible_sprites,
-                                                     x as uint8_t,
-                                                     background_color.is_some());
+    // Like `render_frame`, but also returns the layer each pixel came from, one byte per pixel:
+    // `LAYER_BACKDROP`, `LAYER_BACKGROUND`, `LAYER_SPRITE_BELOW_BG`, or `LAYER_SPRITE_ABOVE_BG`.
+    // A sprite that is behind an opaque background pixel counts as background.
+    pub fn render_frame_with_layers(&mut self) -> (&[uint8_t], &[uint8_t]) {
+        self.render_frame();
+        (self.screen.as_slice(), self.layers.as_slice())
+    }
+
+    // Installs or removes the source of replacement tiles for `render_frame_hd`.
+    pub fn set_tile_replacer(&mut self, replacer: Option<Box<TileReplacer+Send>>) {
+        self.tile_replacer = replacer;
+        self.screen_dirty = true;
+    }
+
+    // Hashes the 16 bytes of pattern data of a tile (0-511, where 256-511 are in the table at
+    // $1000) with 64-bit FNV-1a. This is the key that `TileReplacer`s look tiles up by. The reads
+    // don't show up in the VRAM stats.
+    pub fn chr_hash(&mut self, tile: uint16_t) -> uint64_t {
+        let mut hash = 0xcbf29ce484222325u64;
+        for i in range(0, 16) {
+            let byte = self.vram.chr_loadb_untracked((tile << 4) + i);
+            hash = (hash ^ byte as uint64_t) * 0x100000001b3;
+        }
+        hash
+    }
+
+    // Like `render_frame`, but renders at `scale` times the resolution, as RGB. Each pixel of a
+    // tile that the tile replacer has a replacement for at this scale is drawn from the
+    // replacement; everything else is scaled up from the normal rendering. Replacements whose
+    // pixel data isn't the right size for the scale are ignored.
+    pub fn render_frame_hd(&mut self, scale: uint) -> Vec<uint8_t> {
+        assert!(scale > 0, "scale must be at least 1");
+
+        let width = SCREEN_WIDTH * scale;
+        let mut result = Vec::from_elem(width * SCREEN_HEIGHT * scale * 3, 0u8);
+        let mut hashes: Vec<Option<uint64_t>> = Vec::from_elem(512, None);
+        let hd_tile_len = 8 * scale * 8 * scale * 3;
+
+        let state = self.save_render_state();
+        for y in range(0, SCREEN_HEIGHT) {
+            self.scanline = y as uint16_t;
+            self.render_scanline();
+
+            for x in range(0, SCREEN_WIDTH) {
+                let offset = y * SCREEN_WIDTH + x;
+                let layer = self.layers[offset];
+                let source = match self.hd_tile_source(x, y, layer) {
+                    None => None,
+                    Some(source) => {
+                        if hashes[source.tile as uint].is_none() {
+                            hashes[source.tile as uint] = Some(self.chr_hash(source.tile));
+                        }
+                        let mut palette = [ [ 0, ..3 ], ..4 ];
+                        for i in range(0, 4) {
+                            let entry = source.palette * 4 + i as uint8_t;
+                            let palette_index = self.load_palette(entry);
+                            // `get_masked_color` produces BGR.
+                            let color = self.get_masked_color(palette_index, self.regs.mask);
+                            palette[i] = [ color.b, color.g, color.r ];
+                        }
+                        Some((source, hashes[source.tile as uint].unwrap(), palette))
+                    }
+                };
+
+                let hd_tile = match (source, &self.tile_replacer) {
+                    (Some((source, hash, palette)), &Some(ref replacer)) => {
+                        match replacer.replace(hash, palette) {
+                            Some(hd_tile) if hd_tile.scale == scale &&
+                                             hd_tile.pixels.len() == hd_tile_len => {
+                                Some((source, hd_tile))
+                            }
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+
+                for dy in range(0, scale) {
+                    for dx in range(0, scale) {
+                        let out = ((y * scale + dy) * width + x * scale + dx) * 3;
+                        match hd_tile {
+                            Some((source, hd_tile)) => {
+                                let tx = if source.flip_horizontal { scale - 1 - dx } else { dx };
+                                let ty = if source.flip_vertical { scale - 1 - dy } else { dy };
+                                let tile_x = source.x as uint * scale + tx;
+                                let tile_y = source.y as uint * scale + ty;
+                                let hd_offset = (tile_y * 8 * scale + tile_x) * 3;
+                                result.slice_mut(out, out + 3)
+                                      .clone_from_slice(hd_tile.pixels.slice(hd_offset,
+                                                                             hd_offset + 3));
+                            }
+                            None => {
+                                // The screen is stored in BGR order.
+                                *result.get_mut(out + 0) = self.screen[offset * 3 + 2];
+                                *result.get_mut(out + 1) = self.screen[offset * 3 + 1];
+                                *result.get_mut(out + 2) = self.screen[offset * 3 + 0];
+                            }
+                        }
+                    }
+                }
             }
+        }
+        self.restore_render_state(state);
+        self.screen_dirty = false;
+        self.frame_dirty = false;
 
-            // Combine colors using priority.
-            let color = match (background_color, sprite_color) {
-                (None, None) => backdrop_color,
-                (Some(color), None) => color,
-                (Some(color), Some(SpriteColor { priority: SpritePriority::BelowBg, .. })) => color,
-                (None, Some(SpriteColor { priority: SpritePriority::BelowBg, color: color })) => color,
-                (_, Some(SpriteColor { priority: SpritePriority::AboveBg, color: color })) => color,
-            };
+        result
+    }
 
-            let scanline = self.scanline;
-            self.putpixel(x, scanline as uint, color);
+    // Works out which tile, and which pixel of it, produced the screen pixel at (x, y) on the
+    // scanline just rendered, given the layer the pixel came from. The position is in the tile's
+    // own orientation: for flipped sprites, the flip is noted rather than applied.
+    fn hd_tile_source(&mut self, x: uint, y: uint, layer: uint8_t) -> Option<HdTileSource> {
+        if layer == LAYER_BACKGROUND {
+            let pixel = self.fetch_background_pixel(x as uint8_t, y as uint16_t);
+            let (_, row) = self.background_row(y as uint16_t);
+            let table = self.regs.ctrl.background_pattern_table_addr() >> 4;
+            Some(HdTileSource {
+                tile: pixel.tile as uint16_t | table,
+                palette: pixel.palette,
+                x: ((x as uint16_t + self.scroll_x) % 8) as uint8_t,
+                y: (row % 8) as uint8_t,
+                flip_horizontal: false,
+                flip_vertical: false,
+            })
+        } else if layer == LAYER_SPRITE_BELOW_BG || layer == LAYER_SPRITE_ABOVE_BG {
+            let index = match self.sprite_pixels[x] {
+                Some(pixel) => pixel.index,
+                None => return None,
+            };
+            let sprite = self.make_sprite_info(index as uint16_t);
+            let (tile, row) = self.sprite_tile_row(&sprite, y as uint16_t);
+            let column = x as uint8_t - sprite.x;
+            Some(HdTileSource {
+                tile: tile,
+                palette: sprite.palette(),
+                x: if sprite.flip_horizontal() { 7 - column } else { column },
+                y: row,
+                flip_horizontal: sprite.flip_horizontal(),
+                flip_vertical: sprite.flip_vertical(),
+            })
+        } else {
+            None
         }
     }
 
-    fn start_vblank(&mut self, result: &mut StepResult) {
+    // Renders the current scene with only the given layer and returns it in the same layout as
+    // `screen`. Wherever that layer is transparent, the backdrop shows through. The screen and the
+    // PPU state are left as they were.
+    pub fn screenshot_layer(&mut self, layer: Layer) -> Vec<uint8_t> {
+        let screen = self.screen.to_vec();
+        let indexed_screen = self.indexed_screen.to_vec();
+        let layers = self.layers.to_vec();
+        let (mask, cache) = (self.regs.mask, self.frame_cache);
+        let (screen_dirty, frame_dirty) = (self.screen_dirty, self.frame_dirty);
+
+        *self.regs.mask = match layer {
+            Layer::Background => *mask & !0x10,
+            Layer::Sprites => *mask & !0x08,
+        };
+        self.frame_cache = false;
+        let result = self.render_frame().to_vec();
+
+        self.regs.mask = mask;
+        self.frame_cache = cache;
+        self.screen.clone_from_slice(screen.as_slice());
+        self.indexed_screen.clone_from_slice(indexed_screen.as_slice());
+        self.layers.clone_from_slice(layers.as_slice());
+        self.screen_dirty = screen_dirty;
+        self.frame_dirty = frame_dirty;
+        result
+    }
+
+    // Returns the 6-bit palette index of each pixel of the frame drawn so far, one byte per
+    // pixel, leaving color conversion to the caller. `step` fills this in alongside `screen`.
+    pub fn indexed_screen(&self) -> &[uint8_t] {
+        self.indexed_screen.as_slice()
+    }
+
+    // Like `render_frame`, but returns the frame as palette indices, in the same layout as
+    // `indexed_screen`.
+    pub fn render_frame_indexed(&mut self) -> &[uint8_t] {
+        self.render_frame();
+        self.indexed_screen.as_slice()
+    }
+
+    // The flag is set at dot 1 of scanline 241, which falls in CPU cycle `nmi_cycle`. `cy`
+    // hasn't been advanced past the previous scanline yet.
+    fn start_vblank(&mut self, result: &mut StepResult, nmi_cycle: uint64_t) {
         self.regs.status.set_in_vblank(true);
+        let cy = self.cy;
+        self.log_event(PpuEvent::VblankStart(cy));
+
+        if self.regs.ctrl.vblank_nmi() {
+            result.raise_nmi(nmi_cycle);
+        }
+    }
 
-        // FIXME: Is this correct? Or does it happen on the *next* frame?
+    // The pre-render scanline ends vblank and clears the per-frame sprite flags. On hardware this
+    // happens at dot 1.
+    fn start_prerender(&mut self) {
+        self.regs.status.set_in_vblank(false);
         self.regs.status.set_sprite_zero_hit(false);
+        self.regs.status.set_sprite_overflow(false);
+    }
 
-        if self.regs.ctrl.vblank_nmi() {
-            result.vblank_nmi = true;
+    // Copies the PPUCTRL nametable bits into the scroll position at the points where rendering
+    // reloads it. Writes to PPUCTRL made during a scanline thus take effect on the next one (or,
+    // for the vertical bit, the next frame), and not at all while rendering is disabled.
+    fn reload_scroll(&mut self) {
+        if !self.rendering_enabled() {
+            return;
+        }
+
+        let prerender = self.scanline == (LAST_SCANLINE as uint16_t);
+        if self.scanline < (SCREEN_HEIGHT as uint16_t) || prerender {
+            self.scroll_x = (self.scroll_x & 0xff) | self.next_nametable_x;
+        }
+        if prerender {
+            self.scroll_y = (self.scroll_y & 0xff) | self.next_nametable_y;
+        }
+    }
+
+    // The palette index shown where neither the background nor a sprite is drawn.
+    fn backdrop(&mut self) -> uint8_t {
+        let addr = self.regs.addr.val & 0x3fff;
+        if self.backdrop_hack && !self.rendering_enabled() && addr >= 0x3f00 {
+            self.load_palette((addr & 0x1f) as uint8_t)
+        } else {
+            self.load_palette(0)
+        }
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.regs.mask.show_background() || self.regs.mask.show_sprites()
+    }
+
+    // Whether the PPU is busy rendering right now: rendering is enabled and we're on a visible
+    // or pre-render scanline.
+    fn rendering_active(&self) -> bool {
+        self.rendering_enabled() &&
+            (self.scanline < (SCREEN_HEIGHT as uint16_t) ||
+             self.scanline == (LAST_SCANLINE as uint16_t))
+    }
+
+    // Sprite evaluation reads all of OAM on every visible and pre-render scanline, which refreshes
+    // it. Once OAM has gone unrefreshed for `OAM_DECAY_CYCLES`, one 8-byte row fades to $FF per
+    // scanline, so the corruption spreads gradually.
+    fn decay_oam(&mut self) {
+        if self.rendering_active() {
+            self.oam_refresh_cy = self.cy;
+            return;
+        }
+
+        let elapsed = self.cy - self.oam_refresh_cy;
+        if elapsed < OAM_DECAY_CYCLES {
+            return;
+        }
+
+        let scanlines = (elapsed - OAM_DECAY_CYCLES) * DOTS_PER_CPU_CYCLE /
+            (DOTS_PER_SCANLINE as uint64_t);
+        let row = scanlines % 32;
+        for i in range(0, 8) {
+            self.oam.storeb((row * 8) as uint16_t + i, 0xff);
+        }
+        self.screen_dirty = true;
+    }
+
+    // Moves on to the next scanline. Called once the last dot of the current one has been
+    // processed.
+    fn end_scanline(&mut self, result: &mut StepResult) {
+        // In accurate mode, `tick_prerender` has already skipped the dot if it was going to.
+        let dots = if self.dot_skipped ||
+                (self.render_mode == RenderMode::Fast && self.skips_dot()) {
+            DOTS_PER_SCANLINE as uint64_t - 1
+        } else {
+            DOTS_PER_SCANLINE as uint64_t
+        };
+        self.dot_skipped = false;
+
+        self.reload_scroll();
+
+        if self.scanline < (SCREEN_HEIGHT as uint16_t) && self.should_draw_scanline() {
+            self.render_stats.scanlines_rendered += 1;
+        }
+
+        if self.oam_decay {
+            self.decay_oam();
+        }
+
+        self.scanline += 1;
+
+        {
+            let mut mapper = self.vram.mapper.borrow_mut();
+            if mapper.next_scanline() == MapperResult::Irq {
+                result.interrupts.set_mapper_irq()
+            }
+        }
+
+        if self.scanline == (VBLANK_SCANLINE as uint16_t) {
+            let nmi_cycle = self.dot_cycle(dots + 1);
+            self.start_vblank(result, nmi_cycle);
+        } else if self.scanline == (LAST_SCANLINE as uint16_t) {
+            // When rendering dot by dot, `tick` does this at dot 1 instead.
+            if self.render_mode == RenderMode::Fast {
+                self.start_prerender();
+            }
+        } else if self.scanline > (LAST_SCANLINE as uint16_t) {
+            result.new_frame = true;
+            self.scanline = 0;
+
+            self.frame_count += 1;
+            let frame_count = self.frame_count;
+            self.log_event(PpuEvent::FrameComplete(frame_count));
+
+            self.last_frame_render_stats = self.render_stats;
+            self.render_stats = RenderStats::new();
+
+            // The new frame only needs drawing if something changed during the last one, since
+            // the scanlines drawn before the change show the old picture.
+            self.frame_dirty = self.screen_dirty;
+            self.screen_dirty = false;
+        }
+
+        if self.scanline_breakpoint == Some(self.scanline) {
+            result.breakpoint_hit = true;
+        }
+
+        let cy_dots = self.cy_dots + dots;
+        self.cy += cy_dots / DOTS_PER_CPU_CYCLE;
+        self.cy_dots = cy_dots % DOTS_PER_CPU_CYCLE;
+    }
+
+    // The CPU cycle that the given dot of the current scanline falls in. Dots past the end of
+    // the scanline fall in the next one.
+    fn dot_cycle(&self, dot: uint64_t) -> uint64_t {
+        self.cy + (self.cy_dots + dot) / DOTS_PER_CPU_CYCLE
+    }
+
+    // Whether the pre-render scanline is one dot short: on odd frames, if rendering is enabled.
+    fn skips_dot(&self) -> bool {
+        self.scanline == (LAST_SCANLINE as uint16_t) &&
+            self.frame_count % 2 == 1 &&
+            self.rendering_enabled()
+    }
+
+    // The CPU cycle the next scanline will start on, assuming that rendering isn't turned on or
+    // off in the meantime, which could change whether the pre-render scanline skips a dot.
+    fn next_scanline_cycle(&self) -> uint64_t {
+        let dots = if self.dot_skipped || self.skips_dot() {
+            DOTS_PER_SCANLINE as uint64_t - 1
+        } else {
+            DOTS_PER_SCANLINE as uint64_t
+        };
+        self.dot_cycle(dots)
+    }
+
+    // Advances the PPU by exactly one dot. In the visible region, the pixel for this dot is
+    // rendered using the register state as of this dot, so register writes made between ticks
+    // take effect mid-scanline.
+    pub fn tick(&mut self) -> StepResult {
+        let mut result = StepResult::new();
+        self.take_pending_nmi(&mut result);
+
+        if self.scanline < (SCREEN_HEIGHT as uint16_t) {
+            if self.dot == 0 {
+                self.compute_visible_sprites();
+            } else if self.dot <= (SCREEN_WIDTH as uint16_t) {
+                let x = self.dot as uint - 1;
+                if self.should_draw_scanline() {
+                    self.render_pixel(x);
+                } else {
+                    self.skip_pixel(x);
+                }
+            }
+        } else if self.scanline == (LAST_SCANLINE as uint16_t) {
+            self.tick_prerender();
+        }
+
+        if self.scanline < (SCREEN_HEIGHT as uint16_t) ||
+                self.scanline == (LAST_SCANLINE as uint16_t) {
+            self.tick_sprite_fetch();
+        }
+
+        self.dot += 1;
+        if self.dot == (DOTS_PER_SCANLINE as uint16_t) {
+            self.dot = 0;
+            self.end_scanline(&mut result);
+        }
+
+        result
+    }
+
+    // During dots 257-320, the PPU fetches the patterns of the sprites for the next scanline,
+    // 8 dots per sprite. It always fetches 8 sprites; empty slots fetch tile $FF. Nothing is
+    // drawn from these fetches, since sprites are latched separately, but mappers that watch the
+    // PPU address bus (A12 in particular) see the same accesses as on hardware.
+    fn tick_sprite_fetch(&mut self) {
+        let first_dot = (SCREEN_WIDTH + 1) as uint16_t;
+        if !self.rendering_enabled() || self.dot < first_dot || self.dot >= first_dot + 64 {
+            return;
+        }
+
+        let slot = ((self.dot - first_dot) / 8) as uint;
+        match (self.dot - first_dot) % 8 {
+            0 if slot == 0 => self.find_sprite_fetch_addrs(),
+            4 => {
+                let addr = self.sprite_fetch_addrs[slot];
+                self.vram.loadb(addr);
+            }
+            6 => {
+                let addr = self.sprite_fetch_addrs[slot];
+                self.vram.loadb(addr + 8);
+            }
+            _ => {}
+        }
+    }
+
+    // Works out the pattern addresses that the sprite fetches for the next scanline will read.
+    fn find_sprite_fetch_addrs(&mut self) {
+        let next_scanline = if self.scanline == (LAST_SCANLINE as uint16_t) {
+            0
+        } else {
+            self.scanline + 1
+        };
+
+        // Sprite evaluation copies the sprites on the next scanline into secondary OAM, which is
+        // filled with $FF beforehand. Slots that no sprite fills therefore hold a sprite at
+        // Y=$FF, which is never on screen, using tile $FF.
+        let mut secondary_oam = [ 0xffu8, ..32 ];
+        let mut count = 0u;
+        self.each_sprite(|this, sprite, index| {
+            if sprite.on_scanline(this, next_scanline) {
+                let base = (index as uint16_t) * 4;
+                for i in range(0u, 4) {
+                    secondary_oam[count * 4 + i] = this.oam.loadb(base + i as uint16_t);
+                }
+                count += 1;
+            }
+            count < 8
+        });
+
+        for slot in range(0u, 8) {
+            let mut sprite = SpriteStruct {
+                y: secondary_oam[slot * 4 + 0] as uint16_t + 1,
+                tile_index_byte: secondary_oam[slot * 4 + 1],
+                attribute_byte: secondary_oam[slot * 4 + 2],
+                x: secondary_oam[slot * 4 + 3],
+            };
+            if slot >= count {
+                // Empty slots aren't on the scanline; fetch the top row of their tile.
+                sprite.y = next_scanline;
+            }
+            let (tile, row) = self.sprite_tile_row(&sprite, next_scanline);
+            self.sprite_fetch_addrs[slot] = (tile << 4) + (row as uint16_t);
+        }
+    }
+
+    // The pre-render scanline draws nothing, but it still clears the vblank and sprite flags,
+    // and it is one dot shorter on odd frames when rendering is enabled. Like a visible scanline,
+    // it fetches the first two background tiles of the next line at dots 321-336. We have no
+    // shift registers for these to fill, since the background is fetched again for each pixel,
+    // but mappers that watch the PPU address bus see the reads. The two dummy nametable reads
+    // that follow aren't made.
+    fn tick_prerender(&mut self) {
+        let first_fetch_dot = (SCREEN_WIDTH + 65) as uint16_t;
+        if self.dot == 1 {
+            self.start_prerender();
+        } else if self.rendering_enabled() &&
+                (self.dot == first_fetch_dot || self.dot == first_fetch_dot + 8) {
+            let x = (self.dot - first_fetch_dot) as uint8_t;
+            self.fetch_background_pixel(x, 0);
+        } else if self.dot == (DOTS_PER_SCANLINE as uint16_t) - 2 && self.skips_dot() {
+            // Skip the last dot.
+            self.dot += 1;
+            self.dot_skipped = true;
         }
     }
 
+    fn take_pending_nmi(&mut self, result: &mut StepResult) {
+        if self.nmi_pending {
+            // The NMI was raised by a PPUCTRL write made since the PPU last ran, so it is due now.
+            let cy = self.dot_cycle(self.dot as uint64_t);
+            result.raise_nmi(cy);
+            self.nmi_pending = false;
+        }
+    }
+
+    // Runs the PPU on its own, rendering every frame, until `frame_count` reaches `target`. This
+    // stops at the start of the target frame. Nothing reacts to the vblank NMIs, so this is for
+    // capturing frames from a known state rather than running a game. Fails if the PPU's timing
+    // stalls on the way.
+    pub fn run_to_frame(&mut self, target: uint64_t) -> Result<(),PpuError> {
+        while self.frame_count < target {
+            let next_scanline_cycle = self.next_scanline_cycle();
+            match self.step(next_scanline_cycle).timing_error {
+                Some(err) => return Err(err),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Runs to the end of the current frame in lockstep with the CPU. Before each scanline,
+    // `cpu_step` is called with the cycle the CPU should run up to and the interrupts raised by
+    // the previous scanline, which the CPU should take first. Returns the result of the last
+    // scanline, whose interrupts haven't been passed to `cpu_step` yet. This also returns early
+    // if the PPU's timing stalls, with `timing_error` set.
+    pub fn run_frame_with(&mut self, cpu_step: |uint64_t, InterruptFlags|) -> StepResult {
+        let mut interrupts = InterruptFlags { val: 0 };
+        loop {
+            let run_to_cycle = self.next_scanline_cycle();
+            cpu_step(run_to_cycle, interrupts);
+
+            let result = self.step(run_to_cycle);
+            if result.new_frame || result.timing_error.is_some() {
+                return result;
+            }
+            interrupts = result.interrupts;
+        }
+    }
+
+    // A watchdog for the timing counters. If the scanline or dot ever ends up out of range, the
+    // PPU would never finish another frame and the emulator would hang, so `step` stops and
+    // returns this error instead. It does the same if a pass through its loop doesn't move on
+    // to a new scanline.
+    #[inline(always)]
+    fn check_timing(&self) -> Option<PpuError> {
+        if self.scanline > (LAST_SCANLINE as uint16_t) ||
+                self.dot >= (DOTS_PER_SCANLINE as uint16_t) {
+            Some(self.timing_stalled())
+        } else {
+            None
+        }
+    }
+
+    fn timing_stalled(&self) -> PpuError {
+        PpuError::TimingStalled(self.scanline, self.dot)
+    }
+
     #[inline(never)]
     pub fn step(&mut self, run_to_cycle: uint64_t) -> StepResult {
-        let mut result = StepResult { new_frame: false, vblank_nmi: false, scanline_irq: false };
+        let mut result = StepResult::new();
+        self.take_pending_nmi(&mut result);
         loop {
-            let next_scanline_cycle: uint64_t = self.cy + CYCLES_PER_SCANLINE;
-            if next_scanline_cycle > run_to_cycle {
+            if self.next_scanline_cycle() > run_to_cycle {
                 break;
             }
-
-            if self.scanline < (SCREEN_HEIGHT as uint16_t) {
-                self.render_scanline();
+            result.timing_error = self.check_timing();
+            if result.timing_error.is_some() {
+                return result;
             }
+            let scanline = self.scanline;
 
-            self.scanline += 1;
-
-            {
-                let mut mapper = self.vram.mapper.borrow_mut();
-                if mapper.next_scanline() == MapperResult::Irq {
-                    result.scanline_irq = true
+            if self.render_mode == RenderMode::Fast && self.dot == 0 {
+                if self.scanline < (SCREEN_HEIGHT as uint16_t) {
+                    if self.should_draw_scanline() {
+                        self.render_scanline();
+                    } else {
+                        self.skip_scanline();
+                    }
+                }
+                self.end_scanline(&mut result);
+            } else {
+                // Finish the scanline one dot at a time. This is also how we catch up after
+                // someone has single-stepped into the middle of a scanline with `tick`.
+                loop {
+                    let tick_result = self.tick();
+                    result.merge(tick_result);
+                    if self.dot == 0 {
+                        break;
+                    }
+                    result.timing_error = self.check_timing();
+                    if result.timing_error.is_some() {
+                        return result;
+                    }
                 }
             }
 
-            if self.scanline == (VBLANK_SCANLINE as uint16_t) {
-                self.start_vblank(&mut result);
-            } else if self.scanline == (LAST_SCANLINE as uint16_t) {
-                result.new_frame = true;
-                self.scanline = 0;
-                self.regs.status.set_in_vblank(false);
+            // `cy` has moved on by a scanline, so the scanline must have too.
+            if self.scanline == scanline {
+                result.timing_error = Some(self.timing_stalled());
+                return result;
             }
 
-            self.cy += CYCLES_PER_SCANLINE;
+            if result.breakpoint_hit {
+                return result;
+            }
+        }
 
-            debug_assert(self.cy % CYCLES_PER_SCANLINE == 0, "at even scanline cycle");
+        // In accurate mode, also run the dots of the current scanline that come before
+        // `run_to_cycle`, so that the CPU's next register write lands on the right dot. The last
+        // dot is left to the loop above, since finishing the scanline advances `cy`.
+        if self.render_mode == RenderMode::Accurate {
+            loop {
+                let next_dot = self.dot as uint64_t + 1;
+                let next_dot_cycle = self.dot_cycle(next_dot);
+                let last_dot = next_dot >= (DOTS_PER_SCANLINE as uint64_t);
+                if last_dot || next_dot_cycle > run_to_cycle {
+                    break;
+                }
+                let tick_result = self.tick();
+                result.merge(tick_result);
+            }
         }
 
         return result;
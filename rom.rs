@@ -4,11 +4,28 @@
 // Author: Patrick Walton
 //
 
+use mapper::Mirroring;
+use mapper;
+
+use std::fmt;
 use std::io::File;
 use std::vec::Vec;
 
 use libc::uint8_t;
 
+#[deriving(Copy, PartialEq, Eq)]
+pub enum RomError {
+    UnsupportedMapper(uint8_t),
+}
+
+impl fmt::Show for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::UnsupportedMapper(mapper) => write!(f, "mapper {} is not supported", mapper),
+        }
+    }
+}
+
 pub struct Rom {
     pub header: INesHeader,
     pub prg: Vec<uint8_t>,         // PRG-ROM
@@ -16,7 +33,7 @@ pub struct Rom {
 }
 
 impl Rom {
-    fn from_file(file: &mut File) -> Rom {
+    fn from_file(file: &mut File) -> Result<Rom,RomError> {
         let mut buffer = [ 0, ..16 ];
         file.read_at_least(buffer.len(), &mut buffer).unwrap();
 
@@ -44,19 +61,25 @@ impl Rom {
             0x1a,
         ]);
 
+        // Check the mapper before going any further, so that the frontend can report it instead
+        // of the emulator crashing or drawing garbage.
+        if !mapper::is_supported(header.ines_mapper()) {
+            return Err(RomError::UnsupportedMapper(header.ines_mapper()));
+        }
+
         let mut prg_rom = Vec::from_elem(header.prg_rom_size as uint * 16384, 0u8);
         file.read_at_least(prg_rom.len(), prg_rom.as_mut_slice()).unwrap();
         let mut chr_rom = Vec::from_elem(header.chr_rom_size as uint * 8192, 0u8);
         file.read_at_least(chr_rom.len(), chr_rom.as_mut_slice()).unwrap();
 
-        Rom {
+        Ok(Rom {
             header: header,
             prg: prg_rom,
             chr: chr_rom,
-        }
+        })
     }
 
-    pub fn from_path(path: &Path) -> Rom {
+    pub fn from_path(path: &Path) -> Result<Rom,RomError> {
         Rom::from_file(&mut File::open(path).unwrap())
     }
 }
@@ -84,13 +107,26 @@ impl INesHeader {
     pub fn trainer(&self) -> bool {
         (self.flags_6 & 0x04) != 0
     }
+    // The nametable mirroring the cartridge is wired for. Mappers may override it.
+    pub fn mirroring(&self) -> Mirroring {
+        if (self.flags_6 & 0x01) != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
 
     pub fn to_str(&self) -> String {
-        (format!("PRG-ROM size: {}\nCHR-ROM size: {}\nMapper: {}/{}\nTrainer: {}",
+        (format!("PRG-ROM size: {}\nCHR-ROM size: {}\nMapper: {}/{} ({})\nTrainer: {}",
                  self.prg_rom_size as int,
                  self.chr_rom_size as int,
                  self.mapper() as int,
                  self.ines_mapper() as int,
+                 if mapper::is_supported(self.ines_mapper()) {
+                     "supported"
+                 } else {
+                     "unsupported"
+                 },
                  if self.trainer() {
                      "Yes"
                  } else {
@@ -134,6 +134,14 @@ impl Xorshift {
         Xorshift { x: 123456789, y: 362436069, z: 521288629, w: 88675123 }
     }
 
+    // Returns a generator whose sequence is determined by the given seed.
+    pub fn with_seed(seed: uint64_t) -> Xorshift {
+        let mut rng = Xorshift::new();
+        rng.x = rng.x ^ (seed as uint32_t);
+        rng.y = rng.y ^ ((seed >> 32) as uint32_t);
+        rng
+    }
+
     pub fn next(&mut self) -> uint32_t {
         let t = self.x ^ (self.x << 11);
         self.x = self.y; self.y = self.z; self.z = self.w;